@@ -0,0 +1,628 @@
+use crate::parser::{GroupField, Grouping, QueryCmd};
+use crate::{eval, json_cmp, type_mismatch, EvalMode, JqrError};
+use serde_json::json;
+use serde_json::Value;
+use std::cmp::{Ordering, Reverse};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BinaryHeap, HashMap};
+use std::hash::{Hash, Hasher};
+
+/// Folds values into a single result one at a time, so an aggregating function after `#[...]` or
+/// a leading filter can run over a stream of any length without holding on to its records.
+pub(crate) trait Aggregator {
+    /// Takes in the next value, failing with a `TypeMismatch` when it can't be aggregated.
+    fn push(&mut self, value: Value, mode: EvalMode) -> Result<(), JqrError>;
+
+    fn finish(self: Box<Self>) -> Value;
+}
+
+/// Creates the aggregator of a function from its parsed arguments.
+pub(crate) type FoldFn = fn(&[QueryCmd]) -> Box<dyn Aggregator + '_>;
+
+pub(crate) fn fold_with<A: Aggregator + Default + 'static>(
+    _: &[QueryCmd],
+) -> Box<dyn Aggregator + '_> {
+    Box::<A>::default()
+}
+
+/// Runs every element of `vs` through `agg`.
+pub(crate) fn fold(
+    mut agg: Box<dyn Aggregator + '_>,
+    vs: Vec<Value>,
+    mode: EvalMode,
+) -> Result<Value, JqrError> {
+    for v in vs {
+        agg.push(v, mode)?;
+    }
+    Ok(agg.finish())
+}
+
+/// An aggregator that a value it can't take makes `null` in lenient mode, as an aggregate over an
+/// array with such a value in it is, instead of failing.
+pub(crate) struct Recovering<'a>(Option<Box<dyn Aggregator + 'a>>);
+
+impl<'a> Recovering<'a> {
+    pub(crate) fn new(agg: Box<dyn Aggregator + 'a>) -> Recovering<'a> {
+        Recovering(Some(agg))
+    }
+
+    pub(crate) fn push(&mut self, value: Value, mode: EvalMode) -> Result<(), JqrError> {
+        if let Some(agg) = &mut self.0 {
+            match agg.push(value, mode) {
+                Err(e @ JqrError::TypeMismatch { .. }) => {
+                    mode.recover(e, ())?;
+                    self.0 = None;
+                }
+                r => r?,
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> Value {
+        self.0.map_or(Value::Null, |agg| agg.finish())
+    }
+}
+
+fn number(v: &Value) -> Result<&serde_json::Number, JqrError> {
+    match v {
+        Value::Number(n) => Ok(n),
+        v => Err(type_mismatch("number", v)),
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Count(usize);
+
+impl Aggregator for Count {
+    fn push(&mut self, _: Value, _: EvalMode) -> Result<(), JqrError> {
+        self.0 += 1;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Value {
+        json!(self.0)
+    }
+}
+
+/// Integers add up as integers, as long as they don't overflow, anything else as floats.
+pub(crate) struct Sum {
+    ints: Option<i64>,
+    floats: f64,
+    count: usize,
+}
+
+impl Default for Sum {
+    fn default() -> Self {
+        Sum {
+            ints: Some(0),
+            floats: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Aggregator for Sum {
+    fn push(&mut self, value: Value, _: EvalMode) -> Result<(), JqrError> {
+        let n = number(&value)?;
+        self.ints = self.ints.and_then(|i| i.checked_add(n.as_i64()?));
+        self.floats += n.as_f64().unwrap_or(0.0);
+        self.count += 1;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Value {
+        match self.ints {
+            Some(i) => json!(i),
+            None => json!(self.floats),
+        }
+    }
+}
+
+/// Mean of the numbers, `null` when there were none.
+#[derive(Default)]
+pub(crate) struct Avg(Sum);
+
+impl Aggregator for Avg {
+    fn push(&mut self, value: Value, mode: EvalMode) -> Result<(), JqrError> {
+        self.0.push(value, mode)
+    }
+
+    fn finish(self: Box<Self>) -> Value {
+        match self.0.count {
+            0 => Value::Null,
+            n => json!(self.0.floats / n as f64),
+        }
+    }
+}
+
+/// Smallest value in the order `unique` sorts in, the first of equal ones, `null` when there were
+/// none.
+#[derive(Default)]
+pub(crate) struct Min(Option<Value>);
+
+impl Aggregator for Min {
+    fn push(&mut self, value: Value, _: EvalMode) -> Result<(), JqrError> {
+        match &self.0 {
+            Some(min) if json_cmp(&value, min) != Ordering::Less => (),
+            _ => self.0 = Some(value),
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Value {
+        self.0.unwrap_or(Value::Null)
+    }
+}
+
+/// Largest value, the last of equal ones, `null` when there were none.
+#[derive(Default)]
+pub(crate) struct Max(Option<Value>);
+
+impl Aggregator for Max {
+    fn push(&mut self, value: Value, _: EvalMode) -> Result<(), JqrError> {
+        match &self.0 {
+            Some(max) if json_cmp(&value, max) == Ordering::Less => (),
+            _ => self.0 = Some(value),
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Value {
+        self.0.unwrap_or(Value::Null)
+    }
+}
+
+/// Registers of a `DistinctCount` are picked by this many bits of a value's hash.
+const DISTINCT_PRECISION: u32 = 14;
+const DISTINCT_REGISTERS: usize = 1 << DISTINCT_PRECISION;
+
+/// Approximate number of distinct values, a HyperLogLog sketch taking 16KB whatever the number of
+/// values. Counts are typically within 1% of the exact one.
+pub(crate) struct DistinctCount {
+    registers: Vec<u8>,
+}
+
+impl Default for DistinctCount {
+    fn default() -> Self {
+        DistinctCount {
+            registers: vec![0; DISTINCT_REGISTERS],
+        }
+    }
+}
+
+impl Aggregator for DistinctCount {
+    fn push(&mut self, value: Value, _: EvalMode) -> Result<(), JqrError> {
+        let mut hasher = DefaultHasher::new();
+        match &value {
+            // so that 1 and 1.0 count as the same number, like they compare equal
+            Value::Number(n) => n.as_f64().unwrap_or(0.0).to_bits().hash(&mut hasher),
+            v => v.to_string().hash(&mut hasher),
+        }
+        let hash = hasher.finish();
+        let register = (hash >> (64 - DISTINCT_PRECISION)) as usize;
+        // the bit past the register's ones caps the rank for hashes with a run of zeros that long
+        let rank = ((hash << DISTINCT_PRECISION) | (1 << (DISTINCT_PRECISION - 1))).leading_zeros()
+            as u8
+            + 1;
+        self.registers[register] = self.registers[register].max(rank);
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Value {
+        let m = DISTINCT_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let harmonic: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let estimate = alpha * m * m / harmonic;
+        let empty = self.registers.iter().filter(|r| **r == 0).count();
+        let estimate = if estimate <= 2.5 * m && empty > 0 {
+            // linear counting is more accurate while many registers are still empty
+            m * (m / empty as f64).ln()
+        } else {
+            estimate
+        };
+        json!(estimate.round() as u64)
+    }
+}
+
+/// Approximate `p`th percentile of the numbers, with `p` from 0 to 100, following the P² algorithm
+/// by Jain and Chlamtac. It only keeps five markers, and is exact for up to five numbers, as well
+/// as for the 0th and 100th percentiles.
+pub(crate) struct Percentile {
+    p: f64,
+    count: usize,
+    /// Heights of the markers, the first five numbers until there were five.
+    heights: Vec<f64>,
+    positions: [f64; 5],
+    desired: [f64; 5],
+    increments: [f64; 5],
+}
+
+impl Percentile {
+    pub(crate) fn new(percent: f64) -> Percentile {
+        let p = percent / 100.0;
+        Percentile {
+            p,
+            count: 0,
+            heights: Vec::with_capacity(5),
+            positions: [1.0, 2.0, 3.0, 4.0, 5.0],
+            desired: [1.0, 1.0 + 2.0 * p, 1.0 + 4.0 * p, 3.0 + 2.0 * p, 5.0],
+            increments: [0.0, p / 2.0, p, (1.0 + p) / 2.0, 1.0],
+        }
+    }
+
+    fn parabolic(&self, i: usize, d: f64) -> f64 {
+        let (q, n) = (&self.heights, &self.positions);
+        q[i] + d / (n[i + 1] - n[i - 1])
+            * ((n[i] - n[i - 1] + d) * (q[i + 1] - q[i]) / (n[i + 1] - n[i])
+                + (n[i + 1] - n[i] - d) * (q[i] - q[i - 1]) / (n[i] - n[i - 1]))
+    }
+
+    fn linear(&self, i: usize, d: f64) -> f64 {
+        let j = if d > 0.0 { i + 1 } else { i - 1 };
+        let (q, n) = (&self.heights, &self.positions);
+        q[i] + d * (q[j] - q[i]) / (n[j] - n[i])
+    }
+
+    fn add(&mut self, x: f64) {
+        self.count += 1;
+        if self.count <= 5 {
+            let at = self.heights.partition_point(|h| *h <= x);
+            self.heights.insert(at, x);
+            return;
+        }
+        let q = &mut self.heights;
+        let k = if x < q[0] {
+            q[0] = x;
+            0
+        } else if x >= q[4] {
+            q[4] = x;
+            3
+        } else {
+            (1..5).find(|i| x < q[*i]).map_or(3, |i| i - 1)
+        };
+        for n in &mut self.positions[k + 1..] {
+            *n += 1.0;
+        }
+        for (desired, increment) in self.desired.iter_mut().zip(&self.increments) {
+            *desired += increment;
+        }
+        for i in 1..4 {
+            let d = self.desired[i] - self.positions[i];
+            let n = &self.positions;
+            if (d >= 1.0 && n[i + 1] - n[i] > 1.0) || (d <= -1.0 && n[i - 1] - n[i] < -1.0) {
+                let d = d.signum();
+                let height = self.parabolic(i, d);
+                self.heights[i] = if self.heights[i - 1] < height && height < self.heights[i + 1] {
+                    height
+                } else {
+                    self.linear(i, d)
+                };
+                self.positions[i] += d;
+            }
+        }
+    }
+}
+
+impl Aggregator for Percentile {
+    fn push(&mut self, value: Value, _: EvalMode) -> Result<(), JqrError> {
+        let x = number(&value)?.as_f64().unwrap_or(0.0);
+        self.add(x);
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Value {
+        let q = &self.heights;
+        match self.count {
+            0 => Value::Null,
+            // linear interpolation between the two closest ranks
+            n if n <= 5 => {
+                let rank = self.p * (n - 1) as f64;
+                let (below, above) = (rank.floor() as usize, rank.ceil() as usize);
+                json!(q[below] + (q[above] - q[below]) * (rank - below as f64))
+            }
+            _ if self.p <= 0.0 => json!(q[0]),
+            _ if self.p >= 1.0 => json!(q[4]),
+            _ => json!(q[2]),
+        }
+    }
+}
+
+/// A value ranked by the key its path yielded, then by how early it came, so that the first of
+/// equal keys ranks highest.
+struct Ranked {
+    key: Value,
+    seq: usize,
+    value: Value,
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        json_cmp(&self.key, &other.key).then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ranked {}
+
+/// The `n` values with the largest keys seen so far, for `top(n; path)`. Only those `n` are held
+/// in memory, however many values go through.
+pub(crate) struct TopN<'a> {
+    n: usize,
+    path: &'a QueryCmd,
+    seen: usize,
+    // a min-heap, the lowest ranked value kept is the one pushed out by a better one
+    kept: BinaryHeap<Reverse<Ranked>>,
+}
+
+impl<'a> TopN<'a> {
+    pub(crate) fn new(n: usize, path: &'a QueryCmd) -> TopN<'a> {
+        TopN {
+            n,
+            path,
+            seen: 0,
+            // `n` comes from the query, so the heap only grows with the values actually kept
+            kept: BinaryHeap::new(),
+        }
+    }
+}
+
+impl<'a> Aggregator for TopN<'a> {
+    fn push(&mut self, value: Value, mode: EvalMode) -> Result<(), JqrError> {
+        let key = eval(value.clone(), self.path, mode)?.unwrap_or(Value::Null);
+        self.kept.push(Reverse(Ranked {
+            key,
+            seq: self.seen,
+            value,
+        }));
+        self.seen += 1;
+        if self.kept.len() > self.n {
+            self.kept.pop();
+        }
+        Ok(())
+    }
+
+    /// The values kept, largest key first.
+    fn finish(self: Box<Self>) -> Value {
+        Value::Array(
+            self.kept
+                .into_sorted_vec()
+                .into_iter()
+                .map(|Reverse(ranked)| ranked.value)
+                .collect(),
+        )
+    }
+}
+
+/// Aggregates of the records grouped under one key, `None` where the projection has `.key`.
+struct Group<'a> {
+    key: Value,
+    aggregates: Vec<Option<Recovering<'a>>>,
+}
+
+/// Records grouped by what a path yields for them, for `group_by(path) | { ... }`. Each group
+/// only keeps its aggregates, however many records it has.
+pub(crate) struct Groups<'a> {
+    grouping: &'a Grouping,
+    groups: HashMap<GroupId, Group<'a>>,
+}
+
+/// What `Groups` hashes a key by, as json values can't be hashed.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum GroupId {
+    /// The bits of a number as a float, so that 1 and 1.0 share a group, like they compare equal.
+    Number(u64),
+    /// The json text of any other key.
+    Text(String),
+}
+
+impl GroupId {
+    fn of(key: &Value) -> GroupId {
+        match key {
+            // adding 0.0 turns -0.0 into 0.0
+            Value::Number(n) => GroupId::Number((n.as_f64().unwrap_or(0.0) + 0.0).to_bits()),
+            v => GroupId::Text(v.to_string()),
+        }
+    }
+}
+
+impl<'a> Groups<'a> {
+    pub(crate) fn new(grouping: &'a Grouping) -> Groups<'a> {
+        Groups {
+            grouping,
+            groups: HashMap::new(),
+        }
+    }
+
+    fn new_group(&self, key: Value) -> Group<'a> {
+        let grouping = self.grouping;
+        let aggregates = grouping
+            .fields
+            .iter()
+            .map(|(_, field)| match field {
+                GroupField::Key => None,
+                GroupField::Aggregate(f, args, _) => f.aggregator(args).map(Recovering::new),
+            })
+            .collect();
+        Group { key, aggregates }
+    }
+
+    /// One object per group, in the order of their keys.
+    pub(crate) fn into_objects(self) -> Vec<Value> {
+        let mut groups: Vec<Group> = self.groups.into_values().collect();
+        groups.sort_by(|a, b| json_cmp(&a.key, &b.key));
+        let fields = &self.grouping.fields;
+        groups
+            .into_iter()
+            .map(|group| {
+                let key = group.key;
+                let object = fields
+                    .iter()
+                    .zip(group.aggregates)
+                    .map(|((name, _), agg)| {
+                        let value = agg.map_or_else(|| key.clone(), Recovering::finish);
+                        (name.to_owned(), value)
+                    })
+                    .collect();
+                Value::Object(object)
+            })
+            .collect()
+    }
+}
+
+impl<'a> Aggregator for Groups<'a> {
+    fn push(&mut self, value: Value, mode: EvalMode) -> Result<(), JqrError> {
+        let key = eval(value.clone(), &self.grouping.key, mode)?.unwrap_or(Value::Null);
+        let id = GroupId::of(&key);
+        if !self.groups.contains_key(&id) {
+            let group = self.new_group(key);
+            self.groups.insert(id.clone(), group);
+        }
+        let group = self.groups.get_mut(&id).expect("group was just added");
+        for ((_, field), agg) in self.grouping.fields.iter().zip(&mut group.aggregates) {
+            if let (GroupField::Aggregate(_, _, path), Some(agg)) = (field, agg) {
+                let v = match path {
+                    Some(path) => eval(value.clone(), path, mode)?,
+                    None => Some(value.clone()),
+                };
+                // a path that yields nothing, like a missing `Clicks?`, adds nothing
+                if let Some(v) = v {
+                    agg.push(v, mode)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Value {
+        Value::Array(self.into_objects())
+    }
+}
+
+#[cfg(test)]
+mod aggregate_test {
+    use super::*;
+
+    fn run(agg: impl Aggregator + 'static, vs: impl Iterator<Item = Value>) -> Value {
+        fold(Box::new(agg), vs.collect(), EvalMode::Strict).expect("fold should not fail")
+    }
+
+    #[test]
+    fn distinct_count_test() {
+        let ints = |n: i64| (0..n).map(|i| json!(i));
+        assert_eq!(run(DistinctCount::default(), ints(0)), json!(0));
+        assert_eq!(run(DistinctCount::default(), ints(100)), json!(100));
+        assert_eq!(
+            run(DistinctCount::default(), ints(10).chain(ints(10))),
+            json!(10)
+        );
+        assert_eq!(
+            run(
+                DistinctCount::default(),
+                vec![json!(1), json!(1.0), json!("1"), json!([1])].into_iter()
+            ),
+            json!(3)
+        );
+        for n in &[5_000, 200_000] {
+            let count = run(DistinctCount::default(), ints(*n)).as_f64().unwrap();
+            let error = (count - *n as f64).abs() / *n as f64;
+            assert!(error < 0.02, "counted {} of {}", count, n);
+        }
+    }
+
+    #[test]
+    fn percentile_test() {
+        let nums = |vs: &[f64]| vs.iter().map(|v| json!(v)).collect::<Vec<Value>>();
+        let small = nums(&[4.0, 1.0, 3.0, 2.0]);
+        assert_eq!(
+            run(Percentile::new(50.0), small.clone().into_iter()),
+            json!(2.5)
+        );
+        assert_eq!(
+            run(Percentile::new(0.0), small.clone().into_iter()),
+            json!(1.0)
+        );
+        assert_eq!(run(Percentile::new(100.0), small.into_iter()), json!(4.0));
+        assert_eq!(run(Percentile::new(90.0), std::iter::empty()), Value::Null);
+
+        // a deterministic shuffle of 1..=10000
+        let shuffled = (1..=10_000u64).map(|i| json!((i * 7919) % 10_007));
+        for (p, expected) in &[(50.0, 5_005.0), (90.0, 9_007.0), (99.0, 9_907.0)] {
+            let found = run(Percentile::new(*p), shuffled.clone()).as_f64().unwrap();
+            assert!(
+                (found - expected).abs() < 100.0,
+                "p{}: expected about {}, got {}",
+                p,
+                expected,
+                found
+            );
+        }
+        assert_eq!(
+            run(Percentile::new(100.0), shuffled.clone()),
+            json!(10_006.0)
+        );
+        assert_eq!(run(Percentile::new(0.0), shuffled), json!(1.0));
+
+        let mismatch = fold(
+            Box::new(Percentile::new(50.0)),
+            vec![json!("a")],
+            EvalMode::Strict,
+        );
+        assert!(matches!(mismatch, Err(JqrError::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn top_test() {
+        let path = QueryCmd::KeywordAccess(vec![String::from("k")]);
+        let mut top = TopN::new(2, &path);
+        for i in 0..1000 {
+            top.push(json!({ "i": i, "k": i % 100 }), EvalMode::Strict)
+                .unwrap();
+            assert!(top.kept.len() <= 2);
+        }
+        // of equal keys the first ones win
+        assert_eq!(
+            Box::new(top).finish(),
+            json!([{ "i": 99, "k": 99 }, { "i": 199, "k": 99 }])
+        );
+
+        // a count far beyond the input neither allocates for it nor overflows
+        for n in &[1_000_000_000_000, usize::MAX] {
+            let mut top = TopN::new(*n, &path);
+            for i in 0..3 {
+                top.push(json!({ "k": i }), EvalMode::Strict).unwrap();
+            }
+            assert_eq!(
+                Box::new(top).finish(),
+                json!([{ "k": 2 }, { "k": 1 }, { "k": 0 }])
+            );
+        }
+    }
+
+    #[test]
+    fn sum_avg_min_max_test() {
+        let vs = || vec![json!(3), json!(1.5), json!(2)].into_iter();
+        assert_eq!(run(Sum::default(), vs()), json!(6.5));
+        assert_eq!(run(Sum::default(), (1..4).map(|i| json!(i))), json!(6));
+        assert_eq!(
+            run(Sum::default(), vec![json!(i64::MAX), json!(1)].into_iter()),
+            json!(i64::MAX as f64 + 1.0)
+        );
+        assert_eq!(run(Avg::default(), vs()), json!(6.5 / 3.0));
+        assert_eq!(run(Avg::default(), std::iter::empty()), Value::Null);
+        assert_eq!(run(Min::default(), vs()), json!(1.5));
+        assert_eq!(run(Max::default(), vs()), json!(3));
+        assert_eq!(run(Count::default(), vs()), json!(3));
+    }
+}
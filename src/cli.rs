@@ -0,0 +1,324 @@
+use crate::input::OnError;
+use crate::output::{Indent, OutputFormat};
+use crate::EvalMode;
+
+pub const HELP: &str = "\
+jqr - explore and slice json documents from the command line
+
+USAGE:
+    jqr [OPTIONS] [FILE...] [QUERY]
+    jqr [OPTIONS] -f QUERY_FILE [FILE...]
+
+With no QUERY every input document is pretty printed. With two or more
+positional arguments the last one is the QUERY and the rest are input
+FILEs, read one after the other as a single stream. Without FILEs, or
+with `-`, input is read from stdin.
+
+OPTIONS:
+    -f, --from-file FILE    read the query from FILE, all positional
+                            arguments are then input files
+        --strict            fail on missing keys, out of bounds indices and
+                            type mismatches instead of yielding null
+        --on-error POLICY   what to do with malformed input records:
+                            fail (default), skip or report
+        --error-file PATH   write skipped records report to PATH, implies
+                            --on-error=report
+    -c, --compact           print each value on a single line
+    -r, --raw-output        print strings without quotes
+    -j, --join-output       like -r, but no newline after each value
+        --indent N          indent pretty printed output by N spaces (0-7,
+                            default 2, 0 is the same as -c)
+        --tab               indent pretty printed output with tabs
+    -C, --color-output      always color output, by default only when writing
+                            to a terminal
+    -M, --monochrome-output never color output
+    -h, --help              print this help
+    -V, --version           print version
+        --                  treat every following argument as positional
+
+QUERY LANGUAGE:
+    Commands are chained with `|`, each one is applied to the output of the
+    previous one, for every input document on its own.
+
+    name                    value of key `name`
+    author.login            nested keyword access, keys may contain letters,
+                            digits, `_` and `$`
+    \"Content-Type\".value    quoted keys for any other characters, with json
+    [\"weird key\"]           escapes, a quoted string without a path after it
+                            or brackets around it is a string, not a key
+    ..id  a..id             every `id` at any depth, as an array
+    a.*  a.*.id             every child of an object or array, as an array
+    a.**                    a value and everything nested in it, as an array
+    [0]                     element 0 of an array, [-1] is the last one
+    [1,3,5]                 array of elements 1, 3 and 5
+    [10..20]                elements 10 up to, but excluding, 20, either end
+                            can be left out or negative, e.g. [5..], [-3..]
+    [1:10:2]  [::2]         slices with a step, every 2nd element
+    name?  [5]?             nothing at all instead of null when `name` or
+                            element 5 is missing, `{ a := name? }` leaves `a`
+                            out
+    nick // name // \"-\"     first alternative that is there and not null
+    #[0]  #[1,3]  #[10..]   at the start of a query only, select input
+                            documents by position, takes the same indices and
+                            slices as above
+    .length  has(\"id\")      call a function, see FUNCTIONS, arguments are
+                            literals or queries on the current value
+    Clicks > 0              keep values matching a filter, operators are
+                            =, !=, <, <=, > and >=, values are json literals:
+                            \"strings\", numbers, true, false or null,
+                            strings are ordered lexicographically
+    Clicks > Impressions    compare two paths or computed values
+    msg ~ \"^GET /users\"     keep strings the regex matches anywhere in, also
+                            written `matches`
+    a > 0 and (b = 1 or not c < 2)
+                            combine filters, `not` binds tightest, then
+                            `and`, then `or`
+    { a := x; b := y.z }    build a new object from sub queries
+    if CTR > 0.05 then \"good\" elif CTR > 0 then \"ok\" else \"bad\" end
+                            the branch of the first filter that matches, the
+                            value as it is when none does and there's no else
+    Clicks / Impressions    arithmetic with +, -, *, / and %, integers are
+                            kept where the result is exact, `+` also joins
+                            strings and arrays and merges objects, `-`
+                            removes array elements, `*` deep merges objects
+
+FUNCTIONS:
+    Called as `.name` or `name()`, those taking arguments as `name(a; b)` or
+    `name(a, b)`. Regexes are string literals, compiled once with the query.
+
+    length                  characters of a string, elements of an array or
+                            object, absolute value of a number, 0 for null
+    type                    \"null\", \"boolean\", \"number\", \"string\", \"array\"
+                            or \"object\"
+    has(key)                whether an object has the key, or an array the
+                            index
+    contains(v)             substring of a string, elements of an array or
+                            keys of an object, checked recursively
+    tostring  tonumber      value as json text, number parsed from a string
+    ascii_downcase          string in lower case, ascii_upcase in upper case
+    split(sep)  join(sep)   string split into an array at sep, or array
+                            elements joined into a string with sep between
+    ltrimstr(s)  rtrimstr(s)
+                            string without the prefix or suffix s
+    trim                    string without leading and trailing whitespace
+    startswith(s)           whether a string starts with s, endswith(s)
+                            whether it ends with it
+    substr(from; to)        characters from `from` up to, but excluding, `to`,
+                            negative positions count back from the end
+    test(regex)             whether the regex matches anywhere in a string
+    capture(regex)          named groups of the first match as an object,
+                            nothing at all when it doesn't match
+    scan(regex)             every match, or its groups when there are any
+    sub(regex; s)           string with the first match replaced by s, where
+                            $1 or ${name} stand for a group, gsub replaces
+                            every match
+
+    The ones below work on arrays, after `#[...]` or a leading filter they get
+    an array of all the documents that got through. count, count_distinct,
+    min, max, sum, avg, percentile and top take in the documents one at a time
+    instead, so they run in constant memory on inputs of any size. These and
+    group_by(path) | { ... } also take in every document of the stream when
+    they start the query, `.count` counts the documents. After a path without
+    `#[...]` or a filter in front they run on each document on its own.
+
+    keys  keys_sorted       keys of an object, indices of an array
+    vals  count             values or size of an object or array
+    first  last  reverse    first or last element, elements in reverse order
+    unique  flatten         distinct elements in sorted order, nested arrays
+                            merged into one
+    min  max  sum  avg      smallest, largest, total or mean of the elements,
+                            sum and avg need numbers
+    count_distinct          number of distinct elements, an estimate within
+                            about 1%
+    percentile(p)           pth percentile of numbers, p from 0 to 100, an
+                            estimate for more than 5 of them
+    sort_by(path)           elements ordered by what path yields for each,
+                            sort_by(path; desc) in reverse order
+    group_by(path)          arrays of the elements path yields the same for
+    group_by(path) | { k := .key; n := count; c := sum(Clicks) }
+                            one object per group instead, of .key and
+                            aggregates of its elements, which take the path
+                            to what they aggregate as their last argument,
+                            each group only keeps its aggregates in memory
+    unique_by(path)         first element of each distinct value of path
+    min_by(path)  max_by(path)
+                            element path yields the smallest or largest for
+    top(n; path)            the n elements path yields the largest for, when
+                            streaming only those n are ever held in memory
+
+ENVIRONMENT:
+    JQR_COLORS              colon separated ANSI color codes for null, false,
+                            true, numbers, strings, arrays, objects and object
+                            keys, e.g. \"1;30:0;33:0;33:0;36:0;32:1;39:1;39:1;34\"
+    NO_COLOR                when set, don't color output unless -C is given
+
+EXAMPLES:
+    jqr sample-github.json \"[0] | commit.author\"
+    cat records.json | jqr \"Clicks > 0 | { c := Clicks; d := Date }\"
+";
+
+#[derive(Debug)]
+pub struct CmdArgs {
+    pub(crate) input_files: Vec<String>,
+    pub(crate) query: Option<String>,
+    pub(crate) query_file: Option<String>,
+    pub(crate) mode: EvalMode,
+    pub(crate) on_error: OnError,
+    pub(crate) error_file: Option<String>,
+    pub(crate) output: OutputFormat,
+    /// Force colors on or off, `None` colors output only when stdout is a terminal.
+    pub(crate) color: Option<bool>,
+    pub(crate) show_help: bool,
+    pub(crate) show_version: bool,
+}
+
+fn option_value<'a>(
+    name: &str,
+    inline: Option<&'a str>,
+    rest: &mut impl Iterator<Item = &'a String>,
+) -> Result<&'a str, String> {
+    inline
+        .or_else(|| rest.next().map(|v| v.as_str()))
+        .ok_or(format!("Option {} expects a value", name))
+}
+
+impl CmdArgs {
+    pub fn new(args: &[String]) -> Result<CmdArgs, String> {
+        let mut cmd = CmdArgs {
+            input_files: vec![],
+            query: None,
+            query_file: None,
+            mode: EvalMode::Lenient,
+            on_error: OnError::Fail,
+            error_file: None,
+            output: OutputFormat::default(),
+            color: None,
+            show_help: false,
+            show_version: false,
+        };
+        let mut positional: Vec<&String> = vec![];
+
+        let mut rest = args.iter().skip(1);
+        while let Some(arg) = rest.next() {
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => (name, Some(value)),
+                _ => (arg.as_str(), None),
+            };
+            match name {
+                "--" => positional.extend(rest.by_ref()),
+                "-h" | "--help" => cmd.show_help = true,
+                "-V" | "--version" => cmd.show_version = true,
+                "--strict" => cmd.mode = EvalMode::Strict,
+                "-c" | "--compact" => cmd.output.indent = Indent::Compact,
+                "-r" | "--raw-output" => cmd.output.raw = true,
+                "-j" | "--join-output" => {
+                    cmd.output.raw = true;
+                    cmd.output.join = true;
+                }
+                "--tab" => cmd.output.indent = Indent::Tab,
+                "-C" | "--color-output" => cmd.color = Some(true),
+                "-M" | "--monochrome-output" => cmd.color = Some(false),
+                "--indent" => {
+                    let n = option_value(name, inline, &mut rest)?;
+                    cmd.output.indent = match n.parse::<usize>() {
+                        Ok(0) => Indent::Compact,
+                        Ok(n) if n <= 7 => Indent::Spaces(n),
+                        _ => return Err(format!("--indent expects 0 to 7, got= {}", n)),
+                    };
+                }
+                "-f" | "--from-file" => {
+                    cmd.query_file = Some(option_value(name, inline, &mut rest)?.to_string())
+                }
+                "--on-error" => cmd.on_error = option_value(name, inline, &mut rest)?.parse()?,
+                "--error-file" => {
+                    cmd.on_error = OnError::Report;
+                    cmd.error_file = Some(option_value(name, inline, &mut rest)?.to_string());
+                }
+                _ if name.starts_with('-') && name != "-" => {
+                    return Err(format!("Unknown option= {}, see jqr --help", arg))
+                }
+                _ => positional.push(arg),
+            }
+        }
+
+        let files = match (&cmd.query_file, positional.split_last()) {
+            (Some(_), _) | (None, None) => positional,
+            (None, Some((query, files))) => {
+                if !query.is_empty() {
+                    cmd.query = Some(query.to_string());
+                }
+                files.to_vec()
+            }
+        };
+        cmd.input_files = files.into_iter().cloned().collect();
+        Ok(cmd)
+    }
+}
+
+#[cfg(test)]
+mod cli_test {
+    use super::*;
+
+    fn run_new(args: &[&str]) -> Result<CmdArgs, String> {
+        let args: Vec<String> = std::iter::once("jqr")
+            .chain(args.iter().copied())
+            .map(String::from)
+            .collect();
+        CmdArgs::new(&args)
+    }
+
+    #[test]
+    fn positional_args_test() {
+        let cmd = run_new(&[]).unwrap();
+        assert_eq!((cmd.input_files.len(), cmd.query), (0, None));
+
+        let cmd = run_new(&["[0]"]).unwrap();
+        assert!(cmd.input_files.is_empty());
+        assert_eq!(cmd.query.as_deref(), Some("[0]"));
+
+        let cmd = run_new(&["a.json", "b.json", "--", "-x"]).unwrap();
+        assert_eq!(cmd.input_files, vec!["a.json", "b.json"]);
+        assert_eq!(cmd.query.as_deref(), Some("-x"));
+
+        let cmd = run_new(&["a.json", ""]).unwrap();
+        assert_eq!(cmd.input_files, vec!["a.json"]);
+        assert_eq!(cmd.query, None);
+    }
+
+    #[test]
+    fn options_test() {
+        let cmd = run_new(&["-f", "q.jqr", "a.json", "-", "--strict"]).unwrap();
+        assert_eq!(cmd.query_file.as_deref(), Some("q.jqr"));
+        assert_eq!(cmd.query, None);
+        assert_eq!(cmd.input_files, vec!["a.json", "-"]);
+        assert_eq!(cmd.mode, EvalMode::Strict);
+
+        let cmd = run_new(&["--on-error", "skip", "--from-file=q.jqr"]).unwrap();
+        assert_eq!(cmd.on_error, OnError::Skip);
+        assert_eq!(cmd.query_file.as_deref(), Some("q.jqr"));
+
+        let cmd = run_new(&["--error-file=errors.log", "a.json", "name"]).unwrap();
+        assert_eq!(cmd.on_error, OnError::Report);
+        assert_eq!(cmd.error_file.as_deref(), Some("errors.log"));
+
+        let cmd = run_new(&["-c", "-r", "a.json", "name"]).unwrap();
+        assert_eq!(cmd.output.indent, Indent::Compact);
+        assert!(cmd.output.raw && !cmd.output.join);
+        assert_eq!(
+            run_new(&["--indent", "4"]).unwrap().output.indent,
+            Indent::Spaces(4)
+        );
+        assert_eq!(run_new(&["--tab"]).unwrap().output.indent, Indent::Tab);
+        assert!(run_new(&["-j"]).unwrap().output.join);
+        assert!(run_new(&["--indent=8"]).is_err());
+        assert_eq!(run_new(&[]).unwrap().color, None);
+        assert_eq!(run_new(&["-C"]).unwrap().color, Some(true));
+        assert_eq!(run_new(&["-C", "-M"]).unwrap().color, Some(false));
+
+        assert!(run_new(&["-h"]).unwrap().show_help);
+        assert!(run_new(&["--version"]).unwrap().show_version);
+        assert!(run_new(&["--colour"]).is_err());
+        assert!(run_new(&["-f"]).is_err());
+        assert!(run_new(&["--on-error=sometimes"]).is_err());
+    }
+}
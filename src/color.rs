@@ -0,0 +1,219 @@
+use serde_json::ser::Formatter;
+use std::io::{self, Write};
+
+const RESET: &str = "\x1b[0m";
+
+/// ANSI SGR sequences, like `1;34`, used to color each kind of json token.
+///
+/// The palette can be changed with the `JQR_COLORS` environment variable, a colon separated list
+/// of sequences in the order null, false, true, numbers, strings, arrays, objects, object keys.
+/// Empty or invalid entries keep their default color, e.g. `JQR_COLORS="::::0;31"` only turns
+/// strings red.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Colors {
+    pub null: String,
+    pub falsy: String,
+    pub truthy: String,
+    pub numbers: String,
+    pub strings: String,
+    pub arrays: String,
+    pub objects: String,
+    pub keys: String,
+}
+
+impl Default for Colors {
+    fn default() -> Self {
+        Colors {
+            null: String::from("1;30"),
+            falsy: String::from("0;33"),
+            truthy: String::from("0;33"),
+            numbers: String::from("0;36"),
+            strings: String::from("0;32"),
+            arrays: String::from("1;39"),
+            objects: String::from("1;39"),
+            keys: String::from("1;34"),
+        }
+    }
+}
+
+fn is_sgr(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit() || c == ';')
+}
+
+impl Colors {
+    /// Default palette with the entries set in `spec`, formatted like `JQR_COLORS`, replaced.
+    pub fn parse(spec: &str) -> Colors {
+        let mut colors = Colors::default();
+        let mut slots = [
+            &mut colors.null,
+            &mut colors.falsy,
+            &mut colors.truthy,
+            &mut colors.numbers,
+            &mut colors.strings,
+            &mut colors.arrays,
+            &mut colors.objects,
+            &mut colors.keys,
+        ];
+        for (slot, sgr) in slots.iter_mut().zip(spec.split(':')) {
+            if is_sgr(sgr) {
+                **slot = sgr.to_string();
+            }
+        }
+        colors
+    }
+
+    pub fn from_env() -> Colors {
+        match std::env::var("JQR_COLORS") {
+            Ok(spec) => Colors::parse(&spec),
+            Err(_) => Colors::default(),
+        }
+    }
+}
+
+fn paint<W: ?Sized + Write>(writer: &mut W, sgr: &str) -> io::Result<()> {
+    write!(writer, "\x1b[{}m", sgr)
+}
+
+/// Wraps another formatter, keeping its layout but surrounding every token with color codes.
+pub struct ColorFormatter<'a, F> {
+    inner: F,
+    colors: &'a Colors,
+    in_key: bool,
+}
+
+impl<'a, F: Formatter> ColorFormatter<'a, F> {
+    pub fn new(inner: F, colors: &'a Colors) -> Self {
+        ColorFormatter {
+            inner,
+            colors,
+            in_key: false,
+        }
+    }
+}
+
+impl<'a, F: Formatter> Formatter for ColorFormatter<'a, F> {
+    fn write_null<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        paint(writer, &self.colors.null)?;
+        self.inner.write_null(writer)?;
+        writer.write_all(RESET.as_bytes())
+    }
+
+    fn write_bool<W: ?Sized + Write>(&mut self, writer: &mut W, value: bool) -> io::Result<()> {
+        let sgr = if value {
+            &self.colors.truthy
+        } else {
+            &self.colors.falsy
+        };
+        paint(writer, sgr)?;
+        self.inner.write_bool(writer, value)?;
+        writer.write_all(RESET.as_bytes())
+    }
+
+    fn write_i64<W: ?Sized + Write>(&mut self, writer: &mut W, value: i64) -> io::Result<()> {
+        paint(writer, &self.colors.numbers)?;
+        self.inner.write_i64(writer, value)?;
+        writer.write_all(RESET.as_bytes())
+    }
+
+    fn write_u64<W: ?Sized + Write>(&mut self, writer: &mut W, value: u64) -> io::Result<()> {
+        paint(writer, &self.colors.numbers)?;
+        self.inner.write_u64(writer, value)?;
+        writer.write_all(RESET.as_bytes())
+    }
+
+    fn write_f64<W: ?Sized + Write>(&mut self, writer: &mut W, value: f64) -> io::Result<()> {
+        paint(writer, &self.colors.numbers)?;
+        self.inner.write_f64(writer, value)?;
+        writer.write_all(RESET.as_bytes())
+    }
+
+    fn begin_string<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        if !self.in_key {
+            paint(writer, &self.colors.strings)?;
+        }
+        self.inner.begin_string(writer)
+    }
+
+    fn end_string<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.inner.end_string(writer)?;
+        if !self.in_key {
+            writer.write_all(RESET.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn begin_array<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        paint(writer, &self.colors.arrays)?;
+        self.inner.begin_array(writer)?;
+        writer.write_all(RESET.as_bytes())
+    }
+
+    fn end_array<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        paint(writer, &self.colors.arrays)?;
+        self.inner.end_array(writer)?;
+        writer.write_all(RESET.as_bytes())
+    }
+
+    fn begin_array_value<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()> {
+        self.inner.begin_array_value(writer, first)
+    }
+
+    fn end_array_value<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.inner.end_array_value(writer)
+    }
+
+    fn begin_object<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        paint(writer, &self.colors.objects)?;
+        self.inner.begin_object(writer)?;
+        writer.write_all(RESET.as_bytes())
+    }
+
+    fn end_object<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        paint(writer, &self.colors.objects)?;
+        self.inner.end_object(writer)?;
+        writer.write_all(RESET.as_bytes())
+    }
+
+    fn begin_object_key<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()> {
+        self.inner.begin_object_key(writer, first)?;
+        self.in_key = true;
+        paint(writer, &self.colors.keys)
+    }
+
+    fn end_object_key<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(RESET.as_bytes())?;
+        self.in_key = false;
+        self.inner.end_object_key(writer)
+    }
+
+    fn begin_object_value<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.inner.begin_object_value(writer)
+    }
+
+    fn end_object_value<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.inner.end_object_value(writer)
+    }
+}
+
+#[cfg(test)]
+mod color_test {
+    use super::*;
+
+    #[test]
+    fn parse_colors_test() {
+        let colors = Colors::parse("::::0;31:x:1;35");
+        assert_eq!(colors.strings, "0;31");
+        assert_eq!(colors.arrays, Colors::default().arrays);
+        assert_eq!(colors.objects, "1;35");
+        assert_eq!(colors.null, Colors::default().null);
+        assert_eq!(Colors::parse(""), Colors::default());
+    }
+}
@@ -0,0 +1,174 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Every way a jqr query can fail, from parsing the query string through evaluating it
+/// against the input documents.
+#[derive(Debug)]
+pub enum JqrError {
+    /// Query string is not valid jqr, `span` holds the (start, end) byte offsets into `query`.
+    Parse {
+        message: String,
+        span: (usize, usize),
+        query: String,
+        hint: Option<String>,
+    },
+    /// A command was applied to a json value it cannot operate on, e.g. `[0]` on an object.
+    TypeMismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+    MissingKey(String),
+    IndexOutOfBounds {
+        index: i64,
+        len: usize,
+    },
+    /// `/` or `%` with a zero on the right.
+    DivisionByZero,
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// An input record that isn't valid json, `line` and `column` count from the start of
+    /// `source`.
+    MalformedRecord {
+        source: String,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+}
+
+impl JqrError {
+    pub(crate) fn parse(message: &str, span: (usize, usize)) -> JqrError {
+        JqrError::Parse {
+            message: message.to_string(),
+            span,
+            query: String::new(),
+            hint: None,
+        }
+    }
+
+    /// An I/O error on the file at `path`, which io errors don't mention themselves.
+    pub(crate) fn io_at(path: &str, e: io::Error) -> JqrError {
+        JqrError::Io(io::Error::new(e.kind(), format!("{}: {}", path, e)))
+    }
+
+    pub(crate) fn with_query(self, input: &str) -> JqrError {
+        match self {
+            JqrError::Parse {
+                message,
+                span,
+                hint,
+                ..
+            } => JqrError::Parse {
+                message,
+                span,
+                query: input.to_string(),
+                hint,
+            },
+            e => e,
+        }
+    }
+}
+
+/// Renders a parse error rustc style, echoing the query with the offending span underlined.
+fn fmt_parse_error(
+    f: &mut fmt::Formatter<'_>,
+    message: &str,
+    span: (usize, usize),
+    query: &str,
+    hint: &Option<String>,
+) -> fmt::Result {
+    let start = span.0.min(query.len());
+    let line_start = query[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = query[start..]
+        .find('\n')
+        .map(|i| start + i)
+        .unwrap_or(query.len());
+    let end = span.1.clamp(start, line_end);
+    let offset = query[line_start..start].chars().count();
+    let width = query[start..end].chars().count().max(1);
+
+    writeln!(f, "{}", message)?;
+    writeln!(f, "  |")?;
+    writeln!(
+        f,
+        "  | {}",
+        query[line_start..line_end].trim_end_matches('\r')
+    )?;
+    write!(f, "  | {}{}", " ".repeat(offset), "^".repeat(width))?;
+    if let Some(hint) = hint {
+        write!(f, "\n  |\n  = help: {}", hint)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for JqrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JqrError::Parse {
+                message,
+                span,
+                query,
+                ..
+            } if query.is_empty() => write!(
+                f,
+                "failed to parse query at {}..{}: {}",
+                span.0, span.1, message
+            ),
+            JqrError::Parse {
+                message,
+                span,
+                query,
+                hint,
+            } => fmt_parse_error(f, message, *span, query, hint),
+            JqrError::TypeMismatch { expected, found } => {
+                write!(
+                    f,
+                    "type mismatch, expected {} but found {}",
+                    expected, found
+                )
+            }
+            JqrError::MissingKey(key) => write!(f, "missing key: {}", key),
+            JqrError::IndexOutOfBounds { index, len } => write!(
+                f,
+                "index {} out of bounds for array of length {}",
+                index, len
+            ),
+            JqrError::DivisionByZero => write!(f, "division by zero"),
+            JqrError::Io(e) => write!(f, "I/O error: {}", e),
+            JqrError::Json(e) => write!(f, "invalid json: {}", e),
+            JqrError::MalformedRecord {
+                source,
+                line,
+                column,
+                message,
+            } => write!(
+                f,
+                "invalid json at line {} column {} of {}: {}",
+                line, column, source, message
+            ),
+        }
+    }
+}
+
+impl Error for JqrError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            JqrError::Io(e) => Some(e),
+            JqrError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for JqrError {
+    fn from(e: io::Error) -> Self {
+        JqrError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for JqrError {
+    fn from(e: serde_json::Error) -> Self {
+        JqrError::Json(e)
+    }
+}
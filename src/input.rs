@@ -0,0 +1,495 @@
+use crate::error::JqrError;
+use serde_json::{Deserializer, Value};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::str::FromStr;
+
+/// What to do with an input record that isn't valid json.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    /// Stop at the first malformed record and return its error.
+    Fail,
+    /// Drop malformed records and keep going, only counting them.
+    Skip,
+    /// Like `Skip`, but also write the byte offset and parse error of every dropped record.
+    Report,
+}
+
+impl FromStr for OnError {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(OnError::Fail),
+            "skip" => Ok(OnError::Skip),
+            "report" => Ok(OnError::Report),
+            _ => Err(format!(
+                "Unknown --on-error policy= {}, expected fail, skip or report",
+                s
+            )),
+        }
+    }
+}
+
+/// Hands out the input one line at a time, so that after a malformed record reading can resume
+/// at a line boundary instead of wherever the json parser gave up.
+struct LineReader<R> {
+    inner: R,
+    line: Vec<u8>,
+    pos: usize,
+    line_offset: usize,
+    /// Number of lines before `line`.
+    lines: usize,
+}
+
+impl<R: BufRead> LineReader<R> {
+    fn offset(&self) -> usize {
+        self.line_offset + self.pos
+    }
+
+    /// Line and column, both counted from 1, of the next byte to be read.
+    fn position(&self) -> (usize, usize) {
+        if self.pos == self.line.len() && self.line.ends_with(b"\n") {
+            (self.lines + 2, 1)
+        } else {
+            (self.lines + 1, self.pos + 1)
+        }
+    }
+
+    /// Replaces the current line with the next one, false at the end of the input.
+    fn next_line(&mut self) -> io::Result<bool> {
+        if !self.line.is_empty() {
+            self.lines += 1;
+        }
+        self.line_offset += self.line.len();
+        self.line.clear();
+        self.pos = 0;
+        Ok(self.inner.read_until(b'\n', &mut self.line)? > 0)
+    }
+
+    fn skip_line(&mut self) {
+        self.pos = self.line.len();
+    }
+
+    /// Moves past the rest of a malformed record, its current line and any following ones
+    /// that are indented or only close it, as in pretty-printed input, so reading resumes at
+    /// the next line starting a record. Returns how many of those following lines it dropped.
+    fn skip_record(&mut self) -> io::Result<usize> {
+        self.skip_line();
+        let mut dropped = 0;
+        while self.next_line()? {
+            match self.line.first() {
+                Some(b) if b.is_ascii_whitespace() || *b == b'}' || *b == b']' => {
+                    self.skip_line();
+                    dropped += 1;
+                }
+                _ => break,
+            }
+        }
+        Ok(dropped)
+    }
+
+    fn rewind_line(&mut self) {
+        self.pos = 0;
+    }
+
+    /// True when the parser stopped right at the opening bracket of a fresh line, which in json
+    /// lines input means the previous record was cut short and this line starts the next one.
+    fn at_line_start(&self) -> bool {
+        matches!(
+            self.line[..self.pos]
+                .iter()
+                .filter(|b| !b.is_ascii_whitespace())
+                .collect::<Vec<_>>()
+                .as_slice(),
+            [b'{'] | [b'[']
+        )
+    }
+}
+
+impl<R: BufRead> Read for LineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.line.len() && !self.next_line()? {
+            return Ok(0);
+        }
+        let n = buf.len().min(self.line.len() - self.pos);
+        buf[..n].copy_from_slice(&self.line[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Iterator over the json documents in a reader, dealing with malformed ones as `on_error` says.
+pub struct JsonRecords<R> {
+    rdr: LineReader<R>,
+    source: String,
+    on_error: OnError,
+    report: Box<dyn Write>,
+    skipped: usize,
+    skipped_lines: usize,
+    done: bool,
+}
+
+impl<R: BufRead> JsonRecords<R> {
+    pub fn new(rdr: R, source: &str, on_error: OnError, report: Box<dyn Write>) -> JsonRecords<R> {
+        JsonRecords {
+            rdr: LineReader {
+                inner: rdr,
+                line: vec![],
+                pos: 0,
+                line_offset: 0,
+                lines: 0,
+            },
+            source: source.to_string(),
+            on_error,
+            report,
+            skipped: 0,
+            skipped_lines: 0,
+            done: false,
+        }
+    }
+
+    /// Number of malformed records dropped so far.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Number of lines dropped as the rest of a malformed record, past the one it failed on.
+    pub fn skipped_lines(&self) -> usize {
+        self.skipped_lines
+    }
+
+    fn into_report(self) -> Box<dyn Write> {
+        self.report
+    }
+
+    /// Writes where a malformed record was dropped and why, and which lines after `failed_line`
+    /// went with it, so that none of them disappear without a trace.
+    fn write_report(
+        &mut self,
+        start: usize,
+        error: &JqrError,
+        failed_line: usize,
+        dropped: usize,
+    ) -> io::Result<()> {
+        writeln!(
+            self.report,
+            "skipped malformed record at byte {}: {}",
+            start, error
+        )?;
+        match dropped {
+            0 => Ok(()),
+            1 => writeln!(
+                self.report,
+                "skipped line {} of {} with it",
+                failed_line + 1,
+                self.source
+            ),
+            n => writeln!(
+                self.report,
+                "skipped lines {} to {} of {} with it",
+                failed_line + 1,
+                failed_line + n,
+                self.source
+            ),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for JsonRecords<R> {
+    type Item = Result<Value, JqrError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let base = self.rdr.offset();
+            let (base_line, base_column) = self.rdr.position();
+            let mut stream = Deserializer::from_reader(&mut self.rdr).into_iter::<Value>();
+            let (record, start) = match stream.next() {
+                None => return None,
+                Some(Ok(v)) => return Some(Ok(v)),
+                Some(Err(e)) => (e, base + stream.byte_offset()),
+            };
+            if record.is_io() {
+                self.done = true;
+                return Some(Err(JqrError::Json(record)));
+            }
+
+            // serde counts lines and columns from where this record's deserializer started
+            let (line, column) = match record.line() {
+                1 => (base_line, base_column - 1 + record.column()),
+                l => (base_line + l - 1, record.column()),
+            };
+            let error = JqrError::MalformedRecord {
+                source: self.source.clone(),
+                line,
+                column,
+                message: without_position(&record),
+            };
+            if self.on_error == OnError::Fail {
+                self.done = true;
+                return Some(Err(error));
+            }
+            self.skipped += 1;
+
+            let failed_line = self.rdr.lines + 1;
+            let mut dropped = 0;
+            if record.is_eof() {
+                self.done = true;
+            } else if self.rdr.line_offset > start && self.rdr.at_line_start() {
+                self.rdr.rewind_line();
+            } else {
+                match self.rdr.skip_record() {
+                    Ok(n) => dropped = n,
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(JqrError::Io(e)));
+                    }
+                }
+            }
+            self.skipped_lines += dropped;
+
+            if self.on_error == OnError::Report {
+                if let Err(e) = self.write_report(start, &error, failed_line, dropped) {
+                    self.done = true;
+                    return Some(Err(JqrError::Io(e)));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// serde's description of `e`, without the line and column it ends in.
+fn without_position(e: &serde_json::Error) -> String {
+    let message = e.to_string();
+    let position = format!(" at line {} column {}", e.line(), e.column());
+    match message.strip_suffix(&position) {
+        Some(message) => message.to_string(),
+        None => message,
+    }
+}
+
+/// Reads the records of every input file one after the other as a single stream, stdin stands
+/// in for no files at all or for a `-` among them.
+pub struct InputFiles {
+    paths: std::vec::IntoIter<String>,
+    current: Option<JsonRecords<Box<dyn BufRead>>>,
+    on_error: OnError,
+    report: Option<Box<dyn Write>>,
+    skipped: usize,
+    skipped_lines: usize,
+}
+
+impl InputFiles {
+    pub fn new(paths: &[String], on_error: OnError, report: Box<dyn Write>) -> InputFiles {
+        let paths = if paths.is_empty() {
+            vec![String::from("-")]
+        } else {
+            paths.to_vec()
+        };
+        InputFiles {
+            paths: paths.into_iter(),
+            current: None,
+            on_error,
+            report: Some(report),
+            skipped: 0,
+            skipped_lines: 0,
+        }
+    }
+
+    /// Number of malformed records dropped so far, across all files.
+    pub fn skipped(&self) -> usize {
+        self.skipped + self.current.as_ref().map(|c| c.skipped()).unwrap_or(0)
+    }
+
+    /// Number of lines dropped along with those records, across all files.
+    pub fn skipped_lines(&self) -> usize {
+        self.skipped_lines
+            + self
+                .current
+                .as_ref()
+                .map(|c| c.skipped_lines())
+                .unwrap_or(0)
+    }
+
+    fn open(path: &str) -> Result<Box<dyn BufRead>, JqrError> {
+        if path == "-" {
+            return Ok(Box::new(io::stdin().lock()));
+        }
+        match File::open(path) {
+            Ok(file) => Ok(Box::new(BufReader::new(file))),
+            Err(e) => Err(JqrError::io_at(path, e)),
+        }
+    }
+}
+
+impl Iterator for InputFiles {
+    type Item = Result<Value, JqrError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(records) = self.current.as_mut() {
+                match records.next() {
+                    Some(record) => return Some(record),
+                    None => {
+                        let records = self.current.take()?;
+                        self.skipped += records.skipped();
+                        self.skipped_lines += records.skipped_lines();
+                        self.report = Some(records.into_report());
+                    }
+                }
+            }
+
+            let path = self.paths.next()?;
+            let rdr = match InputFiles::open(&path) {
+                Ok(rdr) => rdr,
+                Err(e) => return Some(Err(e)),
+            };
+            let source = if path == "-" { "<stdin>" } else { &path };
+            let report = self.report.take()?;
+            self.current = Some(JsonRecords::new(rdr, source, self.on_error, report));
+        }
+    }
+}
+
+#[cfg(test)]
+mod input_test {
+    use super::*;
+    use serde_json::json;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const INPUT: &str = "{\"a\": 1}\n{\"a\": 2\n{\"a\": 3}\n{\"a\": x}\n{\n  \"a\": 5\n}\n";
+
+    #[test]
+    fn skip_malformed_records_test() {
+        let report = SharedBuf::default();
+        let mut records = JsonRecords::new(
+            INPUT.as_bytes(),
+            "test",
+            OnError::Report,
+            Box::new(report.clone()),
+        );
+        let values: Vec<Value> = records
+            .by_ref()
+            .collect::<Result<_, _>>()
+            .expect("malformed records should be skipped");
+
+        assert_eq!(
+            values,
+            vec![json!({"a": 1}), json!({"a": 3}), json!({"a": 5})]
+        );
+        assert_eq!(records.skipped(), 2);
+
+        let report = String::from_utf8(report.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        // lines and columns count from the start of the input, not of the record
+        assert_eq!(
+            lines,
+            vec![
+                "skipped malformed record at byte 9: invalid json at line 3 column 1 of test: \
+                 expected `,` or `}`",
+                "skipped malformed record at byte 26: invalid json at line 4 column 7 of test: \
+                 expected value",
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_malformed_pretty_printed_record_test() {
+        let input = "{\n  \"a\": 1\n}\n{\n  \"a\": x,\n  \"b\": [\n    {}\n  ]\n}\n{\"a\": 2} {\"a\": y}\n{\n  \"a\": 3\n}\n";
+        let report = SharedBuf::default();
+        let mut records = JsonRecords::new(
+            input.as_bytes(),
+            "test",
+            OnError::Report,
+            Box::new(report.clone()),
+        );
+        let values: Vec<Value> = records
+            .by_ref()
+            .collect::<Result<_, _>>()
+            .expect("malformed records should be skipped");
+
+        // the rest of a malformed record, indented or closing it, is skipped along with it
+        assert_eq!(
+            values,
+            vec![json!({"a": 1}), json!({"a": 2}), json!({"a": 3})]
+        );
+        assert_eq!(records.skipped(), 2);
+        assert_eq!(records.skipped_lines(), 4);
+        let report = String::from_utf8(report.0.borrow().clone()).unwrap();
+        assert_eq!(
+            report.lines().collect::<Vec<_>>(),
+            vec![
+                "skipped malformed record at byte 13: invalid json at line 5 column 8 of test: \
+                 expected value",
+                "skipped lines 6 to 9 of test with it",
+                "skipped malformed record at byte 56: invalid json at line 10 column 16 of test: \
+                 expected value",
+            ]
+        );
+    }
+
+    #[test]
+    fn report_lines_skipped_with_malformed_record_test() {
+        let input = "{bad\n  {\"a\": 1}\n{\"a\": 2}\n";
+        let report = SharedBuf::default();
+        let mut records = JsonRecords::new(
+            input.as_bytes(),
+            "test",
+            OnError::Report,
+            Box::new(report.clone()),
+        );
+        let values: Vec<Value> = records
+            .by_ref()
+            .collect::<Result<_, _>>()
+            .expect("malformed records should be skipped");
+
+        // an indented line is taken for the rest of the record, so it goes too, but not unnoticed
+        assert_eq!(values, vec![json!({"a": 2})]);
+        assert_eq!(records.skipped(), 1);
+        assert_eq!(records.skipped_lines(), 1);
+        let report = String::from_utf8(report.0.borrow().clone()).unwrap();
+        assert_eq!(
+            report.lines().collect::<Vec<_>>(),
+            vec![
+                "skipped malformed record at byte 0: invalid json at line 1 column 2 of test: \
+                 key must be a string",
+                "skipped line 2 of test with it",
+            ]
+        );
+    }
+
+    #[test]
+    fn fail_on_malformed_record_test() {
+        let mut records = JsonRecords::new(
+            INPUT.as_bytes(),
+            "test",
+            OnError::Fail,
+            Box::new(io::sink()),
+        );
+        assert_eq!(records.next().unwrap().unwrap(), json!({"a": 1}));
+        assert!(matches!(
+            records.next(),
+            Some(Err(JqrError::MalformedRecord {
+                line: 3,
+                column: 1,
+                ..
+            }))
+        ));
+        assert!(records.next().is_none());
+    }
+}
@@ -3,129 +3,267 @@ extern crate pest;
 #[macro_use]
 extern crate pest_derive;
 
-use parser::QueryCmd;
+use aggregate::{
+    fold, fold_with, Aggregator, Avg, Count, DistinctCount, FoldFn, Groups, Max, Min, Percentile,
+    Recovering, Sum, TopN,
+};
+pub use cli::CmdArgs;
+pub use color::Colors;
+pub use error::JqrError;
+use input::InputFiles;
+pub use input::OnError;
+pub use output::{Indent, OutputFormat};
+use output::{JsonWriter, Printer};
+use parser::{ArithOp, CompareOp, PathSegment, Predicate, QueryCmd, Slice};
+use regex::Regex;
 use serde_json::json;
 use serde_json::map::Map;
-use serde_json::Deserializer;
 use serde_json::Value;
 use serde_json::Value::Number;
-use std::collections::HashSet;
-use std::error::Error;
-use std::fs::File;
-use std::io::{self, BufReader};
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, IsTerminal, Write};
+mod aggregate;
+mod cli;
+mod color;
+mod error;
+mod input;
+mod output;
 mod parser;
 
-#[derive(Debug)]
-pub struct CmdArgs {
-    input_file: Option<String>,
-    query: Option<String>,
-}
-
-impl CmdArgs {
-    pub fn new(args: &[String]) -> Result<CmdArgs, String> {
-        match args {
-            [_, input_file, query] => Ok(CmdArgs {
-                input_file: Some(input_file.to_string()),
-                query: Some(query.to_string()),
-            }),
-            [_, query] if query == "" => Ok(CmdArgs {
-                input_file: None,
-                query: None,
-            }),
-            [_, query] => Ok(CmdArgs {
-                input_file: None,
-                query: Some(query.to_string()),
-            }),
-            [_] => Ok(CmdArgs {
-                input_file: None,
-                query: None,
-            }),
-            _ => {
-                return Err(format!(
-                    "Wrong number of arguments passed, jqr expects 0, 1 or 2 args. Passed= {}",
-                    args.len()
-                ))
-            }
+fn parse_cmd(cmd_str: &str) -> Result<QueryCmd, JqrError> {
+    parser::parse(cmd_str)
+}
+
+pub fn read_json_file(file: &str) -> Result<Value, JqrError> {
+    let file = File::open(file)?;
+    let reader = BufReader::new(file);
+    let json: Value = serde_json::from_reader(reader)?;
+
+    Ok(json)
+}
+
+fn type_name(json: &Value) -> &'static str {
+    match json {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// How evaluation treats a command that cannot be applied to the value at hand: an array index
+/// past the end, a missing key, keyword access on a number, `.count` on a string and so on.
+///
+/// `Lenient` (the default) yields `null` in place of the failed lookup, and a filter whose
+/// comparison does not make sense for the value simply doesn't match, so one odd record never
+/// stops a run. `Strict` turns each of those cases into a `JqrError` instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvalMode {
+    Lenient,
+    Strict,
+}
+
+impl EvalMode {
+    fn recover<T>(self, e: JqrError, fallback: T) -> Result<T, JqrError> {
+        match self {
+            EvalMode::Lenient => Ok(fallback),
+            EvalMode::Strict => Err(e),
         }
     }
 }
 
-fn parse_cmd(cmd_str: &String) -> Result<QueryCmd, &'static str> {
-    match parser::parse(&cmd_str) {
-        Ok(cmd) => Ok(cmd),
-        Err(e) => {
-            eprintln!("ERROR parsing cmd={:?} error={:?}", cmd_str, e);
-            Err("Failing now")
+fn type_mismatch(expected: &'static str, found: &Value) -> JqrError {
+    JqrError::TypeMismatch {
+        expected,
+        found: type_name(found),
+    }
+}
+
+/// Position of index `i` in a sequence of `len` elements, negative indices count back from the end.
+fn resolve_index(i: i64, len: usize) -> Option<usize> {
+    if i < 0 {
+        len.checked_sub(i.unsigned_abs() as usize)
+    } else {
+        Some(i as usize).filter(|i| *i < len)
+    }
+}
+
+fn index_array(vs: &[Value], i: i64, mode: EvalMode) -> Result<Value, JqrError> {
+    match resolve_index(i, vs.len()).and_then(|i| vs.get(i)) {
+        Some(v) => Ok(v.to_owned()),
+        None => mode.recover(
+            JqrError::IndexOutOfBounds {
+                index: i,
+                len: vs.len(),
+            },
+            Value::Null,
+        ),
+    }
+}
+
+fn access_keys(json: Value, keys: &[String], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    let mut val = json;
+    for k in keys {
+        val = match val {
+            Value::Object(mut o) => match o.remove(k) {
+                Some(v) => v,
+                None => {
+                    return mode.recover(JqrError::MissingKey(keys.join(".")), Some(Value::Null))
+                }
+            },
+            Value::Null => Value::Null,
+            v => return mode.recover(type_mismatch("object", &v), Some(Value::Null)),
         }
     }
+    Ok(Some(val))
 }
 
-pub fn read_json_file(file: &String) -> Result<Value, Box<dyn Error>> {
-    let file = File::open(file)?;
-    let reader = BufReader::new(file);
-    let json: Value = serde_json::from_reader(reader)?;
+fn push_descendants<'a>(json: &'a Value, out: &mut Vec<&'a Value>) {
+    out.push(json);
+    match json {
+        Value::Array(vs) => vs.iter().for_each(|v| push_descendants(v, out)),
+        Value::Object(o) => o.values().for_each(|v| push_descendants(v, out)),
+        _ => (),
+    }
+}
 
-    Ok(json)
+/// Every value `path` matches in `json`, parents before their children, objects walked in key
+/// order. A wildcard asks for whatever is
+/// there, so values a key is missing from, or that can't have children, are left out rather than
+/// turning into `null` or an error.
+fn search_path(json: &Value, path: &[PathSegment]) -> Value {
+    let mut matches: Vec<&Value> = vec![json];
+    for segment in path {
+        let mut next: Vec<&Value> = vec![];
+        for v in matches {
+            match (segment, v) {
+                (PathSegment::Key(k), Value::Object(o)) => next.extend(o.get(k)),
+                (PathSegment::AnyChild, Value::Array(vs)) => next.extend(vs),
+                (PathSegment::AnyChild, Value::Object(o)) => next.extend(o.values()),
+                (PathSegment::AnyDepth, v) => push_descendants(v, &mut next),
+                _ => (),
+            }
+        }
+        matches = next;
+    }
+    Value::Array(matches.into_iter().cloned().collect())
 }
 
-fn eval(json: Value, query: &QueryCmd) -> Option<Value> {
+fn eval(json: Value, query: &QueryCmd, mode: EvalMode) -> Result<Option<Value>, JqrError> {
     match (json, query) {
-        (v @ Value::Null, _) => Some(v),
-        (v @ Value::Bool(_), _) => Some(v),
-        (v @ Value::Number(_), _) => Some(v),
-        (v @ Value::String(_), _) => Some(v),
-        (Value::Array(vs), QueryCmd::ArrayIndexAccess(idxs)) => {
-            if idxs.len() == 1 {
-                Some(json!(vs[idxs[0]]))
-            } else {
-                let mut arr = vec![];
-
-                for i in idxs {
-                    arr.push(&vs[*i]);
+        (Value::Array(vs), cmd @ QueryCmd::FilterCmd(_)) => {
+            let mut res: Vec<Value> = Vec::new();
+            for v in vs {
+                if let Some(r) = apply_filter(v, cmd, mode)? {
+                    // ToDo this needs fixing this cloning
+                    res.push(r);
                 }
-                Some(json!(arr))
             }
+            Ok(Some(json!(res)))
+        }
+        (json, f @ QueryCmd::FilterCmd(_)) => apply_filter(json, f, mode),
+        // a value on its own is a stream of just one record
+        (json, cmd @ QueryCmd::SelectRecords(_)) => {
+            Ok(Positions::of(cmd).filter(|p| p.selects(0, 1)).map(|_| json))
         }
-        (Value::Object(o), QueryCmd::ListKeys) => {
-            let keys: Vec<&String> = o.keys().collect();
-            Some(json!(keys))
+        (json, QueryCmd::PathSearch(path)) => Ok(Some(search_path(&json, path))),
+        (_, QueryCmd::Literal(v)) => Ok(Some(v.clone())),
+        (json, QueryCmd::Alternative(alternatives)) => {
+            if let Some((last, init)) = alternatives.split_last() {
+                for alternative in init {
+                    match eval(json.clone(), alternative, mode) {
+                        Ok(Some(v)) if !v.is_null() => return Ok(Some(v)),
+                        Ok(_) | Err(JqrError::MissingKey(_)) => (),
+                        Err(JqrError::IndexOutOfBounds { .. }) => (),
+                        Err(JqrError::TypeMismatch { .. }) => (),
+                        Err(e) => return Err(e),
+                    }
+                }
+                eval(json, last, mode)
+            } else {
+                Ok(None)
+            }
         }
-        (Value::Object(o), QueryCmd::ListValues) => {
-            let keys: Vec<&Value> = o.values().collect();
-            Some(json!(keys))
+        // functions decide for themselves what they make of null and of arrays
+        (json, QueryCmd::FunctionCall(f, args)) => match (f.apply, args.split_first()) {
+            (Apply::Paths(apply), _) => apply(json, args, mode),
+            (Apply::Regex(apply), Some((QueryCmd::Pattern(p), rest))) => {
+                let rest = eval_args(&json, rest, mode)?;
+                apply(json, &p.0, &rest, mode)
+            }
+            (Apply::Regex(_), _) => unreachable!(
+                "`{}` was parsed without compiling its regex argument",
+                f.name
+            ),
+            (Apply::Values(apply), _) => {
+                let args = eval_args(&json, args, mode)?;
+                apply(json, &args, mode)
+            }
+        },
+        (_, QueryCmd::Pattern(p)) => Ok(Some(json!(p.0.as_str()))),
+        (json, QueryCmd::Arithmetic(lhs, op, rhs)) => {
+            match (eval(json.clone(), lhs, mode)?, eval(json, rhs, mode)?) {
+                (Some(l), Some(r)) => match arithmetic(l, *op, r) {
+                    Ok(v) => Ok(Some(v)),
+                    Err(e) => mode.recover(e, Some(Value::Null)),
+                },
+                // an optional path that isn't there leaves nothing to compute
+                _ => Ok(None),
+            }
         }
-        (v @ Value::Array(_), QueryCmd::ListValues) => Some(v),
-        (Value::Array(arr), QueryCmd::ListKeys) => {
-            let indices: Vec<usize> = (0..arr.len()).collect();
-            Some(json!(indices))
+        (json, QueryCmd::Conditional(branches, otherwise)) => {
+            for (condition, branch) in branches {
+                if matches_predicate(&json, condition, mode)? {
+                    return eval(json, branch, mode);
+                }
+            }
+            match otherwise {
+                Some(branch) => eval(json, branch, mode),
+                None => Ok(Some(json)),
+            }
         }
-        (Value::Array(arr), QueryCmd::Count) => Some(json!(arr.len())),
-        (Value::Object(obj), QueryCmd::Count) => Some(json!(obj.len())),
-        (Value::Array(vs), cmd @ QueryCmd::KeywordAccess(_)) => {
+        (json, QueryCmd::GroupBy(grouping)) => on_array(json, mode, |vs| {
+            fold(Box::new(Groups::new(grouping)), vs, mode)
+        }),
+        (v @ Value::Null, _) => Ok(Some(v)),
+        // like keyword access, an optional key is looked up in every element
+        (Value::Array(vs), cmd @ QueryCmd::Optional(path))
+            if !matches!(**path, QueryCmd::ArrayIndexAccess(_) | QueryCmd::Slice(_)) =>
+        {
             let mut res: Vec<Value> = Vec::new();
             for v in vs {
-                if let Some(r) = eval(v, cmd) {
+                if let Some(r) = eval(v, cmd, mode)? {
                     res.push(r);
                 }
             }
-            Some(json!(res))
+            Ok(Some(json!(res)))
         }
-        (v @ Value::Object(_), QueryCmd::ArrayIndexAccess(_)) => panic!(format!(
-            "Cannot perform Array index access on an object! Json Found= {}",
-            serde_json::to_string_pretty(&v).unwrap()
-        )),
-        (v @ Value::Object(_), QueryCmd::KeywordAccess(keys)) => {
-            let mut val = &v;
-            for k in keys {
-                val = &val[k];
+        // looked up strictly, so anything absent along the path surfaces as an error to drop on
+        (json, QueryCmd::Optional(path)) => match eval(json, path, EvalMode::Strict) {
+            Err(JqrError::MissingKey(_))
+            | Err(JqrError::IndexOutOfBounds { .. })
+            | Err(JqrError::TypeMismatch { .. }) => Ok(None),
+            r => r,
+        },
+        (json, QueryCmd::MultiCmd(cmds)) => {
+            let mut val = Some(json);
+            for cmd in cmds {
+                // TODO there is got to be a nicer way to do this, flat map over those cmds
+                if let Some(v) = val {
+                    val = eval(v, cmd, mode)?;
+                }
             }
-            Some(json!(*val))
+            Ok(val)
         }
         (json, QueryCmd::TransformIntoObject(prop_mapping)) => {
             let mut props: Map<String, Value> = Map::new();
 
             for (prop_name, prop_access_cmd) in prop_mapping {
-                if let Some(prop_val) = eval(json.clone(), prop_access_cmd) {
+                if let Some(prop_val) = eval(json.clone(), prop_access_cmd, mode)? {
                     props.insert(prop_name.to_owned(), prop_val);
                 }
             }
@@ -143,465 +281,2540 @@ fn eval(json: Value, query: &QueryCmd) -> Option<Value> {
                 let mut res: Vec<Value> = Vec::new();
                 for i in 0..shortest {
                     let mut new_props: Map<String, Value> = Map::new();
-                    for j in 0..names.len() {
-                        new_props.insert(names[j].to_owned(), vals[j][i].to_owned());
+                    for (name, val) in names.iter().zip(vals.iter()) {
+                        new_props.insert(name.to_string(), val[i].to_owned());
                     }
                     res.push(Value::Object(new_props));
                 }
-                Some(json!(res))
+                Ok(Some(json!(res)))
             } else {
-                Some(Value::Object(props))
+                Ok(Some(Value::Object(props)))
             }
         }
-        (Value::Array(vs), cmd @ QueryCmd::FilterCmd(_, _, _)) => {
+        (Value::Array(vs), QueryCmd::ArrayIndexAccess(idxs)) => {
+            if idxs.len() == 1 {
+                Ok(Some(index_array(&vs, idxs[0], mode)?))
+            } else {
+                let mut arr = vec![];
+
+                for i in idxs {
+                    arr.push(index_array(&vs, *i, mode)?);
+                }
+                Ok(Some(json!(arr)))
+            }
+        }
+        (Value::Array(vs), QueryCmd::Slice(slice)) => {
+            let (start, end) = slice.bounds(vs.len());
+            let sliced = vs
+                .into_iter()
+                .skip(start)
+                .take(end - start)
+                .step_by(slice.step);
+            Ok(Some(Value::Array(sliced.collect())))
+        }
+        (Value::Array(vs), cmd @ QueryCmd::KeywordAccess(_)) => {
             let mut res: Vec<Value> = Vec::new();
             for v in vs {
-                if let Some(r) = apply_filter(v, &cmd) {
-                    // ToDo this needs fixing this cloning
+                if let Some(r) = eval(v, cmd, mode)? {
                     res.push(r);
                 }
             }
-            Some(json!(res))
+            Ok(Some(json!(res)))
         }
-        (json, f @ QueryCmd::FilterCmd(_, _, _)) => apply_filter(json, &f),
-        (json, QueryCmd::MultiCmd(cmds)) => {
-            let mut val = Some(json);
-            for cmd in cmds {
-                // TODO there is got to be a nicer way to do this, flat map over those cmds
-                if let Some(v) = val {
-                    val = eval(v, cmd);
-                }
-            }
-            val
+        (v @ Value::Object(_), QueryCmd::KeywordAccess(keys)) => access_keys(v, keys, mode),
+        (v, QueryCmd::ArrayIndexAccess(_)) | (v, QueryCmd::Slice(_)) => {
+            mode.recover(type_mismatch("array", &v), Some(Value::Null))
+        }
+        (v, QueryCmd::KeywordAccess(_)) => {
+            mode.recover(type_mismatch("object", &v), Some(Value::Null))
         }
     }
 }
 
-fn apply_filter(candidate: Value, filter_cmd: &QueryCmd) -> Option<Value> {
-    if let QueryCmd::FilterCmd(cmd, op, value) = filter_cmd {
-        match eval(candidate.clone(), cmd) {
-            Some(Number(n)) if op == "=" && n == value.parse().unwrap() => Some(json!(candidate)),
-            // TODO seems like a classic case of multiple dispatch, extract into separate function, maybe in a trait?
-            Some(Number(n))
-                if op == ">" && n.is_i64() && n.as_i64().unwrap() > value.parse().unwrap() =>
-            {
-                Some(json!(candidate))
-            }
-            Some(Number(n))
-                if op == ">" && n.is_f64() && n.as_f64().unwrap() > value.parse().unwrap() =>
-            {
-                Some(json!(candidate))
-            }
-            Some(Number(n))
-                if op == "<" && n.is_i64() && n.as_i64().unwrap() < value.parse().unwrap() =>
-            {
-                Some(json!(candidate))
-            }
-            Some(Number(n))
-                if op == "<" && n.is_f64() && n.as_f64().unwrap() < value.parse().unwrap() =>
-            {
-                Some(json!(candidate))
-            }
-            Some(serde_json::Value::String(s)) if s == *value => Some(json!(candidate)),
-            _ => None,
-        }
-    } else {
-        None
+fn compare_number(n: &serde_json::Number, v: &serde_json::Number) -> Option<Ordering> {
+    // TODO seems like a classic case of multiple dispatch, extract into separate function, maybe in a trait?
+    match (n.as_i64(), v.as_i64()) {
+        (Some(i), Some(v)) => Some(i.cmp(&v)),
+        _ => n.as_f64()?.partial_cmp(&v.as_f64()?),
     }
 }
 
-fn can_apply_streaming(cmd: &QueryCmd) -> bool {
-    match cmd {
-        QueryCmd::FilterCmd(_, _, _) => true,
-        QueryCmd::KeywordAccess(_) => true,
-        QueryCmd::TransformIntoObject(_) => true,
-        // everything else either needs to accumlate state (ArrayIndexAccess) or terminates computation (keys, Count, listvals)
-        _ => false,
+fn type_order(json: &Value) -> u8 {
+    match json {
+        Value::Null => 0,
+        Value::Bool(_) => 1,
+        Number(_) => 2,
+        Value::String(_) => 3,
+        Value::Array(_) => 4,
+        Value::Object(_) => 5,
     }
 }
 
-fn apply_cmd(v: Value, cmd: &QueryCmd) -> Option<Value> {
-    match cmd {
-        QueryCmd::FilterCmd(_, _, _) => apply_filter(v, cmd),
-        QueryCmd::KeywordAccess(_) => eval(v, cmd),
-        QueryCmd::TransformIntoObject(_) => eval(v, cmd),
+fn cmp_elements<'a>(
+    xs: impl Iterator<Item = &'a Value>,
+    ys: impl Iterator<Item = &'a Value>,
+) -> Ordering {
+    xs.zip(ys)
+        .map(|(x, y)| json_cmp(x, y))
+        .find(|o| *o != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Total order over json values, used to sort and pick minimums. Values of different types order
+/// null, booleans, numbers, strings, arrays, objects, arrays compare element by element and
+/// objects by their sorted keys, then values.
+fn json_cmp(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Bool(x), Value::Bool(y)) => x.cmp(y),
+        (Number(x), Number(y)) => compare_number(x, y).unwrap_or(Ordering::Equal),
+        (Value::String(x), Value::String(y)) => x.cmp(y),
+        (Value::Array(xs), Value::Array(ys)) => {
+            cmp_elements(xs.iter(), ys.iter()).then(xs.len().cmp(&ys.len()))
+        }
+        (Value::Object(xs), Value::Object(ys)) => xs
+            .keys()
+            .cmp(ys.keys())
+            .then_with(|| cmp_elements(xs.values(), ys.values())),
+        _ => type_order(a).cmp(&type_order(b)),
+    }
+}
+
+/// Compares `found` against the right side of a filter. Numbers compare numerically, strings
+/// lexicographically, so ISO dates order as expected. Any other pair of values is only ever
+/// equal or not, ordering them is a type mismatch.
+fn compare(
+    found: Option<Value>,
+    op: CompareOp,
+    other: Option<Value>,
+    mode: EvalMode,
+) -> Result<bool, JqrError> {
+    // only an optional path leaves nothing to compare, which is never equal to anything
+    let (found, other) = match (found, other) {
+        (Some(found), Some(other)) => (found, other),
+        _ => return Ok(op == CompareOp::Ne),
+    };
+    let ordering = match (&found, &other) {
+        (Number(n), Number(v)) => compare_number(n, v),
+        (Value::String(s), Value::String(v)) => Some(s.cmp(v)),
         _ => None,
+    };
+    match (ordering, op) {
+        (Some(ordering), op) => Ok(op.holds(ordering)),
+        (None, CompareOp::Eq) => Ok(found == other),
+        (None, CompareOp::Ne) => Ok(found != other),
+        (None, _) => {
+            let expected = match other {
+                Number(_) | Value::String(_) => type_name(&other),
+                _ => "number or string",
+            };
+            mode.recover(type_mismatch(expected, &found), false)
+        }
     }
 }
 
-fn apply_consecutive_filters(
-    candidate: Value,
-    cmds: Vec<QueryCmd>,
-) -> (Option<Value>, Vec<QueryCmd>) {
-    let mut v = Some(candidate);
-    let rest: Vec<QueryCmd> = cmds
-        .iter()
-        .skip_while(|c| can_apply_streaming(c))
-        .map(|c| c.to_owned())
-        .collect();
-    for cmd in cmds.iter().take_while(|c| can_apply_streaming(c)) {
-        v = v.and_then(|j| apply_cmd(j, cmd));
+/// One side of a comparison, literals are used as they are rather than evaluated against a copy
+/// of every record.
+fn comparand(candidate: &Value, cmd: &QueryCmd, mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    match cmd {
+        QueryCmd::Literal(v) => Ok(Some(v.clone())),
+        _ => eval(candidate.clone(), cmd, mode),
     }
-    (v, rest)
 }
 
-fn post_streaming_aggregation(
-    json_rows: Vec<Value>,
-    agg_cmd: &Option<QueryCmd>,
-    mut write_json: impl FnMut(&Value),
-) -> () {
-    if let Some(agg_cmd) = agg_cmd {
-        if let Some(jv) = eval(Value::Array(json_rows), &agg_cmd) {
-            write_json(&jv);
+/// Evaluates `predicate` against `candidate`, `and` and `or` only look at their right side when
+/// the left one didn't already decide the outcome.
+fn matches_predicate(
+    candidate: &Value,
+    predicate: &Predicate,
+    mode: EvalMode,
+) -> Result<bool, JqrError> {
+    match predicate {
+        Predicate::Compare(lhs, op, rhs) => compare(
+            comparand(candidate, lhs, mode)?,
+            *op,
+            comparand(candidate, rhs, mode)?,
+            mode,
+        ),
+        Predicate::Matches(cmd, pattern) => match eval(candidate.clone(), cmd, mode)? {
+            Some(Value::String(s)) => Ok(pattern.0.is_match(&s)),
+            None => Ok(false),
+            Some(v) => mode.recover(type_mismatch("string", &v), false),
+        },
+        Predicate::And(l, r) => {
+            Ok(matches_predicate(candidate, l, mode)? && matches_predicate(candidate, r, mode)?)
         }
+        Predicate::Or(l, r) => {
+            Ok(matches_predicate(candidate, l, mode)? || matches_predicate(candidate, r, mode)?)
+        }
+        Predicate::Not(p) => Ok(!matches_predicate(candidate, p, mode)?),
     }
 }
 
-//out: &mut dyn io::Write,
-//https://stackoverflow.com/a/47606476
-fn streaming_eval(
-    json_iter: impl Iterator<Item = Value>,
-    query: QueryCmd,
-    mut write_json: impl FnMut(&Value),
-) -> Result<(), Box<dyn Error>> {
-    match &query {
-        QueryCmd::ArrayIndexAccess(idx) => {
-            let idx: HashSet<&usize> = idx.into_iter().collect();
-            json_iter
-                .enumerate()
-                .map(|(i, jv)| idx.get(&i).map(|_| jv))
-                .filter(|j| j.is_some())
-                .map(|j| write_json(&j.unwrap()))
-                .collect()
-        }
-        f @ QueryCmd::FilterCmd(_, _, _) => json_iter
-            .map(|json| apply_filter(json, f))
-            .filter(|jv| jv.is_some())
-            .map(|j| write_json(&j.unwrap()))
-            .for_each(drop),
-        q @ QueryCmd::MultiCmd(_) => {
-            if let QueryCmd::MultiCmd(cmds) = q {
-                match &cmds[0] {
-                    QueryCmd::ArrayIndexAccess(idx) => {
-                        let mut leftover_jv_buffer: Vec<Value> = vec![];
-                        let mut agg_cmd: Option<QueryCmd> = None; //Need to init to sth
-                                                                  // println!("json_iter.coount: {}", json_iter.collect::<Vec<Value>>().len());
-                        let idx: HashSet<&usize> = idx.into_iter().collect();
-                        json_iter
-                            .enumerate()
-                            .map(|(i, jv)| idx.get(&i).map(|_| jv))
-                            .filter(|j| j.is_some())
-                            .map(|j| j.unwrap())
-                            .map(|json| apply_consecutive_filters(json, cmds[1..].to_vec()))
-                            .filter(|(jv, _)| jv.is_some())
-                            .for_each(|(jv, cmds)| {
-                                if let Some(jv) = jv {
-                                    // println!("jv={:?}, cmds={:?}", jv, cmds);
-                                    if cmds.len() > 0 {
-                                        if agg_cmd.is_none() {
-                                            agg_cmd = Some(QueryCmd::MultiCmd(cmds));
-                                        }
-                                        leftover_jv_buffer.push(jv);
-                                    } else {
-                                        write_json(&jv)
-                                    }
-                                }
-                            });
-                        // .collect();
-                        if leftover_jv_buffer.len() > 0 {
-                            post_streaming_aggregation(leftover_jv_buffer, &agg_cmd, write_json)
-                        }
-                    }
-                    QueryCmd::FilterCmd(_, _, _) => {
-                        let mut leftover_jv_buffer: Vec<Value> = vec![];
-                        let mut agg_cmd: Option<QueryCmd> = None;
-
-                        json_iter
-                            .map(|json| apply_consecutive_filters(json, cmds.to_vec()))
-                            .filter(|(jv, _)| jv.is_some())
-                            .for_each(|(jv, cmds)| {
-                                if let Some(jv) = jv {
-                                    if cmds.len() > 0 {
-                                        if agg_cmd.is_none() {
-                                            agg_cmd = Some(QueryCmd::MultiCmd(cmds));
-                                        }
-                                        leftover_jv_buffer.push(jv);
-                                    } else {
-                                        write_json(&jv)
-                                    }
-                                }
-                            });
-                        if leftover_jv_buffer.len() > 0 {
-                            post_streaming_aggregation(leftover_jv_buffer, &agg_cmd, write_json)
-                        }
-                    }
+fn apply_filter(
+    candidate: Value,
+    filter_cmd: &QueryCmd,
+    mode: EvalMode,
+) -> Result<Option<Value>, JqrError> {
+    if let QueryCmd::FilterCmd(predicate) = filter_cmd {
+        let matched = matches_predicate(&candidate, predicate, mode)?;
+        Ok(if matched { Some(candidate) } else { None })
+    } else {
+        Ok(None)
+    }
+}
 
-                    _ => {
-                        let mut sliced_json = json_iter.collect::<Vec<Value>>();
-                        // loop over cmds in multi_cmd and apply each
-                        for cmd in cmds {
-                            sliced_json = sliced_json
-                                .iter()
-                                .map(|jv| eval(jv.to_owned(), cmd))
-                                .filter(|jv| jv.is_some())
-                                .map(|jv| jv.unwrap())
-                                .collect::<Vec<Value>>();
-                        }
-                        // finally print what was collected
-                        sliced_json.iter().for_each(|jv| write_json(&jv));
-                    }
-                }
-            }
+/// `x op y` on two numbers. Integers stay integers as long as the result is a whole number that
+/// fits, anything else is computed with floats.
+fn number_arithmetic(
+    x: &serde_json::Number,
+    op: ArithOp,
+    y: &serde_json::Number,
+) -> Result<Value, JqrError> {
+    if let (Some(a), Some(b)) = (x.as_i64(), y.as_i64()) {
+        let exact = match op {
+            ArithOp::Div | ArithOp::Rem if b == 0 => return Err(JqrError::DivisionByZero),
+            ArithOp::Add => a.checked_add(b),
+            ArithOp::Sub => a.checked_sub(b),
+            ArithOp::Mul => a.checked_mul(b),
+            ArithOp::Div => a.checked_rem(b).filter(|r| *r == 0).and(a.checked_div(b)),
+            ArithOp::Rem => a.checked_rem(b),
+        };
+        if let Some(v) = exact {
+            return Ok(json!(v));
         }
-        q => json_iter
-            .map(|jv| {
-                if let Some(jv) = eval(jv, q) {
-                    write_json(&jv)
-                }
-            })
-            .collect(),
     }
+    let (a, b) = (x.as_f64().unwrap_or(0.0), y.as_f64().unwrap_or(0.0));
+    Ok(json!(match op {
+        ArithOp::Div | ArithOp::Rem if b == 0.0 => return Err(JqrError::DivisionByZero),
+        ArithOp::Add => a + b,
+        ArithOp::Sub => a - b,
+        ArithOp::Mul => a * b,
+        ArithOp::Div => a / b,
+        ArithOp::Rem => a % b,
+    }))
+}
 
-    Ok(())
+/// Objects merged recursively, where both have an object under the same key those are merged too,
+/// otherwise `b`'s value wins.
+fn deep_merge(mut a: Map<String, Value>, b: Map<String, Value>) -> Map<String, Value> {
+    for (k, v) in b {
+        let merged = match (a.remove(&k), v) {
+            (Some(Value::Object(x)), Value::Object(y)) => Value::Object(deep_merge(x, y)),
+            (_, v) => v,
+        };
+        a.insert(k, merged);
+    }
+    a
 }
 
-fn print_json(val: &Value) {
-    // TODO figure out how to consume Result from write!
-    // clearly this is not quite right, yet
-    if let Ok(s) = serde_json::to_string_pretty(val) {
-        println!("{}", s);
-    } else {
-        println!("{}", val.to_string());
-    }
-}
-
-pub fn eval_cmd(cmd: CmdArgs) -> Result<(), Box<dyn Error>> {
-    match (&cmd.input_file, cmd.query.map(|query| parse_cmd(&query))) {
-        (_, Some(Err(msg))) => println!("Failed at cmd parsing with error= {}", msg),
-        (None, Some(Ok(cmd))) => {
-            let std_in = io::stdin();
-            let rdr = std_in.lock();
-            let json_iter = Deserializer::from_reader(rdr)
-                .into_iter::<Value>()
-                .map(|v| v.unwrap());
-            streaming_eval(json_iter, cmd, print_json)?;
-            ()
-        }
-        (Some(input_file), Some(Ok(cmd))) => {
-            let file = File::open(input_file)?;
-            let json_iter = Deserializer::from_reader(BufReader::new(file))
-                .into_iter::<Value>()
-                .map(|v| v.unwrap());
-            streaming_eval(json_iter, cmd, print_json)?;
-            ()
-        }
-        (None, None) => {
-            let stdin = io::stdin();
-            Deserializer::from_reader(stdin.lock())
-                .into_iter::<Value>()
-                .map(|jv| print_json(&jv.unwrap()))
-                .for_each(drop);
-            ()
-        }
-        (Some(input_file), None) => {
-            let file = File::open(input_file)?;
-            Deserializer::from_reader(BufReader::new(file))
-                .into_iter::<Value>()
-                .map(|jv| print_json(&jv.unwrap()))
-                .for_each(drop);
-            ()
+/// `l op r` following jq: besides numbers, `+` concatenates strings and arrays, merges objects
+/// and leaves the other side when one is `null`, `-` removes elements from an array and `*`
+/// merges objects recursively.
+fn arithmetic(l: Value, op: ArithOp, r: Value) -> Result<Value, JqrError> {
+    match (l, op, r) {
+        (Number(x), op, Number(y)) => number_arithmetic(&x, op, &y),
+        (Value::Null, ArithOp::Add, v) | (v, ArithOp::Add, Value::Null) => Ok(v),
+        (Value::String(x), ArithOp::Add, Value::String(y)) => Ok(Value::String(x + &y)),
+        (Value::Array(mut xs), ArithOp::Add, Value::Array(ys)) => {
+            xs.extend(ys);
+            Ok(Value::Array(xs))
         }
-    };
-    Ok(())
+        (Value::Object(mut x), ArithOp::Add, Value::Object(y)) => {
+            x.extend(y);
+            Ok(Value::Object(x))
+        }
+        (Value::Array(xs), ArithOp::Sub, Value::Array(ys)) => Ok(Value::Array(
+            xs.into_iter().filter(|x| !ys.contains(x)).collect(),
+        )),
+        (Value::Object(x), ArithOp::Mul, Value::Object(y)) => Ok(Value::Object(deep_merge(x, y))),
+        (l, op, r) => {
+            let expected = match (&l, op) {
+                (Value::String(_), ArithOp::Add) => "string",
+                (Value::Array(_), ArithOp::Add | ArithOp::Sub) => "array",
+                (Value::Object(_), ArithOp::Add | ArithOp::Mul) => "object",
+                _ => "number",
+            };
+            let found = if type_name(&l) == expected { &r } else { &l };
+            Err(type_mismatch(expected, found))
+        }
+    }
 }
 
-#[cfg(test)]
-mod eval_test {
-    use super::*;
-    use serde_json::Value;
+/// Evaluates function arguments against the value the function is applied to, a missing one is
+/// `null`.
+fn eval_args(json: &Value, args: &[QueryCmd], mode: EvalMode) -> Result<Vec<Value>, JqrError> {
+    args.iter()
+        .map(|arg| Ok(eval(json.clone(), arg, mode)?.unwrap_or(Value::Null)))
+        .collect()
+}
 
-    fn sample_json(i: i32) -> Value {
-        json!({ "i": i, "name": "John Doe", "Revenue": 3223.0, "Collections": 10 + i, "age": 3 + i})
-    }
+/// Signature of a builtin, called with the current value and its arguments already evaluated
+/// against it.
+type BuiltinFn = fn(Value, &[Value], EvalMode) -> Result<Option<Value>, JqrError>;
 
-    #[test]
-    fn eval_cmd_test() {
-        let query_cmd = "[23..100] | age > 18 | {Idx := i; N := name; Rv := Revenue; C := Collections} | Rv > 1500.5 | C > 50 | Idx < 50";
-        let json_iter = (1..100).map(|i| sample_json(i));
+/// Signature of a builtin whose first argument is a regex, it gets the regex compiled when the
+/// query was parsed followed by the rest of the arguments.
+type RegexFn = fn(Value, &Regex, &[Value], EvalMode) -> Result<Option<Value>, JqrError>;
 
-        let mut buffer: Vec<Value> = Vec::new();
-        let value_collector = |jv: &Value| {
-            buffer.push(jv.to_owned());
-        };
+/// Signature of a builtin that evaluates its arguments itself, against each element of an array
+/// rather than the array, like the path in `sort_by(path)`.
+type PathFn = fn(Value, &[QueryCmd], EvalMode) -> Result<Option<Value>, JqrError>;
 
-        let parse_res = parse_cmd(&query_cmd.to_string());
-        let cmd = parse_res.expect("parse_cmd should not fail");
-        streaming_eval(json_iter, cmd, value_collector)
-            .expect("streaming_eval shouldn't throw errors");
+#[derive(Clone, Copy)]
+enum Apply {
+    Values(BuiltinFn),
+    Regex(RegexFn),
+    Paths(PathFn),
+}
 
-        assert_ne!(buffer.len(), 0);
-        assert_eq!(buffer.len(), 9); // because  C > 50 | Idx < 50
+/// A function queries call as `name(args)`, or as `.name` when it takes no arguments.
+pub(crate) struct Builtin {
+    pub(crate) name: &'static str,
+    pub(crate) arity: usize,
+    /// How many arguments past `arity` may be given as well, like the order of `sort_by`.
+    pub(crate) optional: usize,
+    /// Works on a whole array, so after `#[...]` or a leading filter it gets the array of every
+    /// record that got through, rather than each record on its own.
+    aggregates: bool,
+    apply: Apply,
+    /// Checks the parsed arguments beyond their number, for arguments that have to be literals.
+    check: fn(&[QueryCmd]) -> Result<(), String>,
+    /// Aggregates a stream of records one by one, instead of getting an array of all of them.
+    fold: Option<FoldFn>,
+}
 
-        //TODO should really clean this up
-        let first_result = buffer[0].as_object().expect("should be json object");
-        //  assert_eq!(buffer[0], sample_json(1));
-        assert_eq!(
-            first_result.get("N").expect("N should not be empty"),
-            "John Doe"
-        );
-        assert_eq!(
-            first_result
-                .get("Rv")
-                .expect("Rv should not be empty")
-                .as_f64()
-                .expect("Rv should by float64")
-                > 1500.5,
-            true
-        );
-        assert_eq!(
-            first_result
-                .get("C")
-                .expect("C should not be empty")
-                .as_i64()
-                .expect("C should by int64")
-                > 50,
-            true
-        );
-        let value_index = first_result
-            .get("Idx")
-            .expect("Idx should not be empty")
-            .as_i64()
-            .expect("Idx should by int64");
-        assert_eq!(23 <= value_index, true);
-        assert_eq!(value_index < 100, true);
+impl Builtin {
+    const fn per_value(name: &'static str, arity: usize, apply: BuiltinFn) -> Builtin {
+        Builtin {
+            name,
+            arity,
+            optional: 0,
+            aggregates: false,
+            apply: Apply::Values(apply),
+            check: any_args,
+            fold: None,
+        }
     }
 
-    #[test]
-    fn truth_table_test() {
-        let json = r#" {
-        "i": 12,
-        "name":"John Doe",
-        "Revenue": 12}"#;
+    const fn aggregate(name: &'static str, arity: usize, apply: BuiltinFn) -> Builtin {
+        Builtin {
+            name,
+            arity,
+            optional: 0,
+            aggregates: true,
+            apply: Apply::Values(apply),
+            check: any_args,
+            fold: None,
+        }
+    }
 
-        let truth_table = vec![
-            ("[0]", json, 1, json),
-            ("[9]", json, 10, json),
-            ("[]", json, 1, ""),
-            // ("", json, 1, ""),
-            ("[22]", json, 10, ""),
-        ];
+    const fn with_regex(name: &'static str, arity: usize, apply: RegexFn) -> Builtin {
+        Builtin {
+            name,
+            arity,
+            optional: 0,
+            aggregates: false,
+            apply: Apply::Regex(apply),
+            check: any_args,
+            fold: None,
+        }
+    }
 
-        let empty_json = json!("");
-        for (cmd, input, input_size, expected) in truth_table {
-            let json_iter = (0..input_size).map(|_| serde_json::from_str(input).unwrap());
+    const fn by_path(name: &'static str, arity: usize, apply: PathFn) -> Builtin {
+        Builtin {
+            name,
+            arity,
+            optional: 0,
+            aggregates: true,
+            apply: Apply::Paths(apply),
+            check: any_args,
+            fold: None,
+        }
+    }
 
-            let mut buffer: Vec<Value> = Vec::new();
-            let value_collector = |jv: &Value| {
-                buffer.push(jv.to_owned());
-            };
-            let parse_res = parse_cmd(&cmd.to_string());
-            let cmd = parse_res.expect("parse_cmd should not fail");
-            streaming_eval(json_iter, cmd, value_collector)
-                .expect("streaming_eval shouldn't throw errors");
+    const fn optional_args(self, optional: usize) -> Builtin {
+        Builtin { optional, ..self }
+    }
 
-            let result = buffer.get(0).unwrap_or(&empty_json);
+    const fn checked(self, check: fn(&[QueryCmd]) -> Result<(), String>) -> Builtin {
+        Builtin { check, ..self }
+    }
 
-            let expected: Value = serde_json::from_str(expected).unwrap_or(json!(""));
-            assert_eq!(result, &expected, "Expected: {}, got: {}", expected, result);
+    const fn folding(self, fold: FoldFn) -> Builtin {
+        Builtin {
+            fold: Some(fold),
+            ..self
         }
     }
 
-    #[test]
-    fn multi_cmd_index_slicing_test() {
-        //TODO write a test to handle this, a streaming index slicing and then .count!
-        // let cmd = "[100..300] | name | .count";
-        let cmd = "[100..300] | name ";
-        let input_size = 300;
-        let expected = 200;
+    /// Whether the first argument is a regex, which has to be a string literal.
+    pub(crate) fn takes_regex(&self) -> bool {
+        matches!(self.apply, Apply::Regex(_))
+    }
 
-        let json_iter = (0..input_size).map(|i| sample_json(i));
+    /// Whether it can aggregate records one at a time.
+    pub(crate) fn folds(&self) -> bool {
+        self.fold.is_some()
+    }
 
-        let mut buffer: Vec<Value> = Vec::new();
-        let value_collector = |jv: &Value| {
-            buffer.push(jv.to_owned());
-        };
-        let parse_res = parse_cmd(&cmd.to_string());
-        let cmd = parse_res.expect("parse_cmd should not fail");
-        streaming_eval(json_iter, cmd, value_collector)
-            .expect("streaming_eval shouldn't throw errors");
+    pub(crate) fn aggregator<'a>(&self, args: &'a [QueryCmd]) -> Option<Box<dyn Aggregator + 'a>> {
+        self.fold.map(|fold| fold(args))
+    }
 
-        let result = buffer.len();
+    pub(crate) fn check_args(&self, args: &[QueryCmd]) -> Result<(), String> {
+        (self.check)(args)
+    }
+}
 
-        assert_eq!(result, expected, "Expected: {}, got: {}", expected, result);
+fn any_args(_: &[QueryCmd]) -> Result<(), String> {
+    Ok(())
+}
+
+impl PartialEq for Builtin {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
     }
+}
 
-    #[test]
-    fn multi_cmd_streaming_with_count_after_test() {
-        // TODO handle this, a streaming index slicing and then .count! This currently doesn't work
-        let cmd = "[100..300] | name | .count";
-        let input_size = 300;
-        let expected = json!(200);
+impl Eq for Builtin {}
 
-        let json_iter = (0..input_size).map(|i| sample_json(i));
+impl std::fmt::Debug for Builtin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
 
-        let mut buffer: Vec<Value> = Vec::new();
-        let value_collector = |jv: &Value| {
-            buffer.push(jv.to_owned());
-        };
-        let parse_res = parse_cmd(&cmd.to_string());
-        let cmd = parse_res.expect("parse_cmd should not fail");
-        streaming_eval(json_iter, cmd, value_collector)
-            .expect("streaming_eval shouldn't throw errors");
+/// Every function a query can call, looked up by name when the query is parsed.
+static BUILTINS: &[Builtin] = &[
+    Builtin::per_value("length", 0, length),
+    Builtin::per_value("type", 0, value_type),
+    Builtin::per_value("has", 1, has),
+    Builtin::per_value("contains", 1, contains),
+    Builtin::per_value("tostring", 0, tostring),
+    Builtin::per_value("tonumber", 0, tonumber),
+    Builtin::per_value("ascii_downcase", 0, ascii_downcase),
+    Builtin::per_value("ascii_upcase", 0, ascii_upcase),
+    Builtin::per_value("split", 1, split),
+    Builtin::per_value("join", 1, join),
+    Builtin::per_value("ltrimstr", 1, ltrimstr),
+    Builtin::per_value("rtrimstr", 1, rtrimstr),
+    Builtin::per_value("trim", 0, trim),
+    Builtin::per_value("startswith", 1, startswith),
+    Builtin::per_value("endswith", 1, endswith),
+    Builtin::per_value("substr", 2, substr),
+    Builtin::with_regex("test", 1, test_regex),
+    Builtin::with_regex("capture", 1, capture),
+    Builtin::with_regex("scan", 1, scan),
+    Builtin::with_regex("sub", 2, sub),
+    Builtin::with_regex("gsub", 2, gsub),
+    Builtin::aggregate("keys", 0, keys),
+    Builtin::aggregate("keys_sorted", 0, keys_sorted),
+    Builtin::aggregate("vals", 0, vals),
+    Builtin::aggregate("count", 0, count).folding(fold_with::<Count>),
+    Builtin::aggregate("count_distinct", 0, count_distinct).folding(fold_with::<DistinctCount>),
+    Builtin::aggregate("first", 0, first),
+    Builtin::aggregate("last", 0, last),
+    Builtin::aggregate("reverse", 0, reverse),
+    Builtin::aggregate("unique", 0, unique),
+    Builtin::aggregate("flatten", 0, flatten),
+    Builtin::aggregate("min", 0, min).folding(fold_with::<Min>),
+    Builtin::aggregate("max", 0, max).folding(fold_with::<Max>),
+    Builtin::aggregate("sum", 0, sum).folding(fold_with::<Sum>),
+    Builtin::aggregate("avg", 0, avg).folding(fold_with::<Avg>),
+    Builtin::by_path("sort_by", 1, sort_by)
+        .optional_args(1)
+        .checked(check_sort_by),
+    Builtin::by_path("group_by", 1, group_by),
+    Builtin::by_path("unique_by", 1, unique_by),
+    Builtin::by_path("min_by", 1, min_by),
+    Builtin::by_path("max_by", 1, max_by),
+    Builtin::by_path("top", 2, top)
+        .checked(check_top)
+        .folding(fold_top),
+    Builtin::by_path("percentile", 1, percentile)
+        .checked(check_percentile)
+        .folding(fold_percentile),
+];
 
-        let empty_json = &json!("");
-        let result = buffer.get(0).unwrap_or(empty_json);
+pub(crate) fn builtin(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|f| f.name == name)
+}
 
-        assert_eq!(result, &expected, "Expected: {}, got: {}", expected, result);
+/// Handles what `f` makes of a mismatched value, or argument, as `mode` says. Any other error is
+/// passed on as it is.
+fn recover_mismatch(
+    result: Result<Value, JqrError>,
+    mode: EvalMode,
+) -> Result<Option<Value>, JqrError> {
+    match result {
+        Ok(v) => Ok(Some(v)),
+        Err(e @ JqrError::TypeMismatch { .. }) => mode.recover(e, Some(Value::Null)),
+        Err(e) => Err(e),
     }
+}
 
-    #[test]
-    fn multi_cmd_streaming_starting_with_filter_with_count_after_test() {
-        // TODO handle this, a streaming index slicing and then .count! This currently doesn't work
-        let cmd = "i < 100 | name | .count";
-        let input_size = 300;
-        let expected = json!(100);
+/// Applies `f` to an array, `null` passes through like it does for keyword and index access.
+fn on_array(
+    json: Value,
+    mode: EvalMode,
+    f: impl FnOnce(Vec<Value>) -> Result<Value, JqrError>,
+) -> Result<Option<Value>, JqrError> {
+    match json {
+        Value::Array(vs) => recover_mismatch(f(vs), mode),
+        v @ Value::Null => Ok(Some(v)),
+        v => mode.recover(type_mismatch("array", &v), Some(Value::Null)),
+    }
+}
 
-        let json_iter = (0..input_size).map(|i| sample_json(i));
+/// Applies `f` to a string, `null` passes through.
+fn on_string(
+    json: Value,
+    mode: EvalMode,
+    f: impl FnOnce(String) -> Result<Value, JqrError>,
+) -> Result<Option<Value>, JqrError> {
+    match json {
+        Value::String(s) => recover_mismatch(f(s), mode),
+        v @ Value::Null => Ok(Some(v)),
+        v => mode.recover(type_mismatch("string", &v), Some(Value::Null)),
+    }
+}
 
-        let mut buffer: Vec<Value> = Vec::new();
-        let value_collector = |jv: &Value| {
-            buffer.push(jv.to_owned());
-        };
-        let parse_res = parse_cmd(&cmd.to_string());
-        let cmd = parse_res.expect("parse_cmd should not fail");
-        streaming_eval(json_iter, cmd, value_collector)
-            .expect("streaming_eval shouldn't throw errors");
+fn string_arg(args: &[Value], i: usize) -> Result<&str, JqrError> {
+    match &args[i] {
+        Value::String(s) => Ok(s),
+        v => Err(type_mismatch("string", v)),
+    }
+}
 
-        let empty_json = &json!("");
-        let result = buffer.get(0).unwrap_or(empty_json);
+fn integer_arg(args: &[Value], i: usize) -> Result<i64, JqrError> {
+    args[i]
+        .as_i64()
+        .ok_or_else(|| type_mismatch("integer", &args[i]))
+}
 
-        assert_eq!(result, &expected, "Expected: {}, got: {}", expected, result);
+fn length(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    match json {
+        Value::Null => Ok(Some(json!(0))),
+        Value::String(s) => Ok(Some(json!(s.chars().count()))),
+        Value::Array(vs) => Ok(Some(json!(vs.len()))),
+        Value::Object(o) => Ok(Some(json!(o.len()))),
+        Number(n) => match n.as_i64() {
+            Some(i) => Ok(Some(json!(i.unsigned_abs()))),
+            None => Ok(Some(json!(n.as_f64().map(f64::abs)))),
+        },
+        v => mode.recover(
+            type_mismatch("string, array or object", &v),
+            Some(Value::Null),
+        ),
     }
+}
 
-    #[test]
-    fn iter_slicing_test() {
-        let cmd = "[10..30]";
-        let input_size = 30;
+fn value_type(json: Value, _: &[Value], _: EvalMode) -> Result<Option<Value>, JqrError> {
+    Ok(Some(json!(type_name(&json))))
+}
+
+fn has(json: Value, args: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    match (json, &args[0]) {
+        (Value::Object(o), Value::String(k)) => Ok(Some(json!(o.contains_key(k)))),
+        (Value::Array(vs), Number(n)) => {
+            let found = n.as_u64().filter(|i| (*i as usize) < vs.len()).is_some();
+            Ok(Some(json!(found)))
+        }
+        (v @ Value::Null, _) => Ok(Some(v)),
+        (Value::Object(_), k) => mode.recover(type_mismatch("string", k), Some(Value::Null)),
+        (Value::Array(_), i) => mode.recover(type_mismatch("number", i), Some(Value::Null)),
+        (v, _) => mode.recover(type_mismatch("object or array", &v), Some(Value::Null)),
+    }
+}
+
+/// Substrings for strings, for arrays every element of `needle` contained in some element of
+/// `json`, for objects every key of `needle` present with a value contained in `json`'s.
+fn value_contains(json: &Value, needle: &Value) -> bool {
+    match (json, needle) {
+        (Value::String(s), Value::String(sub)) => s.contains(sub.as_str()),
+        (Value::Array(vs), Value::Array(ns)) => {
+            ns.iter().all(|n| vs.iter().any(|v| value_contains(v, n)))
+        }
+        (Value::Object(o), Value::Object(no)) => no
+            .iter()
+            .all(|(k, n)| o.get(k).is_some_and(|v| value_contains(v, n))),
+        (Number(a), Number(b)) => compare_number(a, b) == Some(Ordering::Equal),
+        (a, b) => a == b,
+    }
+}
+
+fn contains(json: Value, args: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    let needle = &args[0];
+    match json {
+        v @ Value::Null => Ok(Some(v)),
+        v if type_name(&v) == type_name(needle) => Ok(Some(json!(value_contains(&v, needle)))),
+        v => mode.recover(type_mismatch(type_name(needle), &v), Some(Value::Null)),
+    }
+}
+
+fn tostring(json: Value, _: &[Value], _: EvalMode) -> Result<Option<Value>, JqrError> {
+    match json {
+        s @ Value::String(_) => Ok(Some(s)),
+        v => Ok(Some(Value::String(v.to_string()))),
+    }
+}
+
+fn tonumber(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    match json {
+        n @ Number(_) | n @ Value::Null => Ok(Some(n)),
+        Value::String(s) => match serde_json::from_str(s.trim()) {
+            Ok(n @ Number(_)) => Ok(Some(n)),
+            _ => mode.recover(
+                type_mismatch("number or numeric string", &Value::String(s)),
+                Some(Value::Null),
+            ),
+        },
+        v => mode.recover(type_mismatch("number or string", &v), Some(Value::Null)),
+    }
+}
+
+fn ascii_downcase(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_string(json, mode, |s| Ok(json!(s.to_ascii_lowercase())))
+}
+
+fn ascii_upcase(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_string(json, mode, |s| Ok(json!(s.to_ascii_uppercase())))
+}
+
+/// Splitting on an empty separator yields every character on its own.
+fn split(json: Value, args: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_string(json, mode, |s| match string_arg(args, 0)? {
+        "" => Ok(json!(s.chars().map(String::from).collect::<Vec<String>>())),
+        sep => Ok(json!(s.split(sep).collect::<Vec<&str>>())),
+    })
+}
+
+/// Numbers and booleans are joined as json writes them, `null` as an empty string.
+fn join(json: Value, args: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_array(json, mode, |vs| {
+        let sep = string_arg(args, 0)?;
+        let parts = vs
+            .into_iter()
+            .map(|v| match v {
+                Value::String(s) => Ok(s),
+                Value::Null => Ok(String::new()),
+                v @ Number(_) | v @ Value::Bool(_) => Ok(v.to_string()),
+                v => Err(type_mismatch("string, number, boolean or null", &v)),
+            })
+            .collect::<Result<Vec<String>, JqrError>>()?;
+        Ok(json!(parts.join(sep)))
+    })
+}
+
+fn ltrimstr(json: Value, args: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_string(json, mode, |s| {
+        let prefix = string_arg(args, 0)?;
+        Ok(json!(s.strip_prefix(prefix).unwrap_or(&s)))
+    })
+}
+
+fn rtrimstr(json: Value, args: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_string(json, mode, |s| {
+        let suffix = string_arg(args, 0)?;
+        Ok(json!(s.strip_suffix(suffix).unwrap_or(&s)))
+    })
+}
+
+fn trim(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_string(json, mode, |s| Ok(json!(s.trim())))
+}
+
+fn startswith(json: Value, args: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_string(json, mode, |s| {
+        Ok(json!(s.starts_with(string_arg(args, 0)?)))
+    })
+}
+
+fn endswith(json: Value, args: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_string(json, mode, |s| Ok(json!(s.ends_with(string_arg(args, 0)?))))
+}
+
+/// Characters from the first argument up to, but excluding, the second, negative positions
+/// count back from the end like in slices.
+fn substr(json: Value, args: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_string(json, mode, |s| {
+        let slice = Slice {
+            start: Some(integer_arg(args, 0)?),
+            end: Some(integer_arg(args, 1)?),
+            step: 1,
+        };
+        let (start, end) = slice.bounds(s.chars().count());
+        Ok(json!(s
+            .chars()
+            .skip(start)
+            .take(end - start)
+            .collect::<String>()))
+    })
+}
+
+/// Whether the regex matches anywhere in the string.
+fn test_regex(
+    json: Value,
+    re: &Regex,
+    _: &[Value],
+    mode: EvalMode,
+) -> Result<Option<Value>, JqrError> {
+    on_string(json, mode, |s| Ok(json!(re.is_match(&s))))
+}
+
+/// Named groups of the first match as an object, groups that took no part in it are `null`.
+/// A string the regex doesn't match yields nothing at all, like a missing `path?`.
+fn capture(
+    json: Value,
+    re: &Regex,
+    _: &[Value],
+    mode: EvalMode,
+) -> Result<Option<Value>, JqrError> {
+    match json {
+        Value::String(s) => Ok(re.captures(&s).map(|caps| {
+            let groups = re.capture_names().flatten().map(|name| {
+                let group = caps.name(name).map(|m| json!(m.as_str()));
+                (name.to_string(), group.unwrap_or(Value::Null))
+            });
+            Value::Object(groups.collect())
+        })),
+        v @ Value::Null => Ok(Some(v)),
+        v => mode.recover(type_mismatch("string", &v), Some(Value::Null)),
+    }
+}
+
+/// Every match in the string, as the matched text, or as an array of its groups when the regex
+/// has any.
+fn scan(json: Value, re: &Regex, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_string(json, mode, |s| {
+        let matches = re.captures_iter(&s).map(|caps| match caps.len() {
+            1 => json!(&caps[0]),
+            _ => json!(caps
+                .iter()
+                .skip(1)
+                .map(|m| m.map(|m| m.as_str()))
+                .collect::<Vec<Option<&str>>>()),
+        });
+        Ok(Value::Array(matches.collect()))
+    })
+}
+
+/// Replaces the first match, `$1` or `${name}` in the replacement stand for a group.
+fn sub(json: Value, re: &Regex, args: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_string(json, mode, |s| {
+        Ok(json!(re.replace(&s, string_arg(args, 0)?)))
+    })
+}
+
+/// Like `sub`, but replaces every match.
+fn gsub(
+    json: Value,
+    re: &Regex,
+    args: &[Value],
+    mode: EvalMode,
+) -> Result<Option<Value>, JqrError> {
+    on_string(json, mode, |s| {
+        Ok(json!(re.replace_all(&s, string_arg(args, 0)?)))
+    })
+}
+
+fn keys(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    match json {
+        Value::Object(o) => Ok(Some(json!(o.keys().collect::<Vec<&String>>()))),
+        Value::Array(vs) => Ok(Some(json!((0..vs.len()).collect::<Vec<usize>>()))),
+        v @ Value::Null => Ok(Some(v)),
+        v => mode.recover(type_mismatch("array or object", &v), Some(Value::Null)),
+    }
+}
+
+/// Same as `keys`, but doesn't rely on objects keeping their keys sorted.
+fn keys_sorted(json: Value, args: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    match keys(json, args, mode)? {
+        Some(Value::Array(mut ks)) => {
+            ks.sort_by(json_cmp);
+            Ok(Some(Value::Array(ks)))
+        }
+        r => Ok(r),
+    }
+}
+
+fn vals(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    match json {
+        Value::Object(o) => Ok(Some(Value::Array(o.into_iter().map(|(_, v)| v).collect()))),
+        v @ Value::Array(_) | v @ Value::Null => Ok(Some(v)),
+        v => mode.recover(type_mismatch("array or object", &v), Some(Value::Null)),
+    }
+}
+
+fn count(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    match json {
+        Value::Object(o) => Ok(Some(json!(o.len()))),
+        Value::Array(vs) => Ok(Some(json!(vs.len()))),
+        v @ Value::Null => Ok(Some(v)),
+        v => mode.recover(type_mismatch("array or object", &v), Some(Value::Null)),
+    }
+}
+
+fn first(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_array(json, mode, |vs| {
+        Ok(vs.into_iter().next().unwrap_or(Value::Null))
+    })
+}
+
+fn last(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_array(json, mode, |mut vs| Ok(vs.pop().unwrap_or(Value::Null)))
+}
+
+fn reverse(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    match json {
+        Value::String(s) => Ok(Some(json!(s.chars().rev().collect::<String>()))),
+        json => on_array(json, mode, |mut vs| {
+            vs.reverse();
+            Ok(Value::Array(vs))
+        }),
+    }
+}
+
+fn unique(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_array(json, mode, |mut vs| {
+        vs.sort_by(json_cmp);
+        vs.dedup_by(|a, b| json_cmp(a, b) == Ordering::Equal);
+        Ok(Value::Array(vs))
+    })
+}
+
+fn flatten_into(vs: Vec<Value>, out: &mut Vec<Value>) {
+    for v in vs {
+        match v {
+            Value::Array(nested) => flatten_into(nested, out),
+            v => out.push(v),
+        }
+    }
+}
+
+fn flatten(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_array(json, mode, |vs| {
+        let mut flat = vec![];
+        flatten_into(vs, &mut flat);
+        Ok(Value::Array(flat))
+    })
+}
+
+fn min(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_array(json, mode, |vs| fold(Box::<Min>::default(), vs, mode))
+}
+
+fn max(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_array(json, mode, |vs| fold(Box::<Max>::default(), vs, mode))
+}
+
+fn sum(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_array(json, mode, |vs| fold(Box::<Sum>::default(), vs, mode))
+}
+
+fn avg(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_array(json, mode, |vs| fold(Box::<Avg>::default(), vs, mode))
+}
+
+fn count_distinct(json: Value, _: &[Value], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_array(json, mode, |vs| {
+        fold(Box::<DistinctCount>::default(), vs, mode)
+    })
+}
+
+/// Each element paired with what `path` yields for it, `null` when it yields nothing.
+fn keyed(vs: Vec<Value>, path: &QueryCmd, mode: EvalMode) -> Result<Vec<(Value, Value)>, JqrError> {
+    vs.into_iter()
+        .map(|v| Ok((eval(v.clone(), path, mode)?.unwrap_or(Value::Null), v)))
+        .collect()
+}
+
+/// Elements with equal keys grouped together, groups in the order of their keys.
+fn sorted_groups(
+    vs: Vec<Value>,
+    path: &QueryCmd,
+    mode: EvalMode,
+) -> Result<Vec<Vec<Value>>, JqrError> {
+    let mut keyed = keyed(vs, path, mode)?;
+    keyed.sort_by(|(a, _), (b, _)| json_cmp(a, b));
+    let mut groups: Vec<(Value, Vec<Value>)> = vec![];
+    for (key, v) in keyed {
+        match groups.last_mut() {
+            Some((last, group)) if json_cmp(last, &key) == Ordering::Equal => group.push(v),
+            _ => groups.push((key, vec![v])),
+        }
+    }
+    Ok(groups.into_iter().map(|(_, group)| group).collect())
+}
+
+/// Whether the order argument of `sort_by` is `desc`, it's `asc` when left out.
+fn is_descending(order: Option<&QueryCmd>) -> Result<bool, String> {
+    match order {
+        None => Ok(false),
+        Some(QueryCmd::KeywordAccess(k)) if *k == ["asc"] => Ok(false),
+        Some(QueryCmd::KeywordAccess(k)) if *k == ["desc"] => Ok(true),
+        Some(_) => Err(String::from("sort order has to be `asc` or `desc`")),
+    }
+}
+
+fn check_sort_by(args: &[QueryCmd]) -> Result<(), String> {
+    is_descending(args.get(1)).map(|_| ())
+}
+
+/// Ties keep their order either way, the sort is stable.
+fn sort_by(json: Value, args: &[QueryCmd], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    let descending = is_descending(args.get(1))
+        .unwrap_or_else(|e| unreachable!("`check_sort_by` let an invalid order through: {}", e));
+    on_array(json, mode, |vs| {
+        let mut keyed = keyed(vs, &args[0], mode)?;
+        if descending {
+            keyed.sort_by(|(a, _), (b, _)| json_cmp(b, a));
+        } else {
+            keyed.sort_by(|(a, _), (b, _)| json_cmp(a, b));
+        }
+        Ok(Value::Array(keyed.into_iter().map(|(_, v)| v).collect()))
+    })
+}
+
+fn group_by(json: Value, args: &[QueryCmd], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_array(json, mode, |vs| {
+        let groups = sorted_groups(vs, &args[0], mode)?;
+        Ok(Value::Array(groups.into_iter().map(Value::Array).collect()))
+    })
+}
+
+/// The first element of each key, in the order of their keys.
+fn unique_by(json: Value, args: &[QueryCmd], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_array(json, mode, |vs| {
+        let groups = sorted_groups(vs, &args[0], mode)?;
+        Ok(Value::Array(
+            groups
+                .into_iter()
+                .filter_map(|g| g.into_iter().next())
+                .collect(),
+        ))
+    })
+}
+
+fn min_by(json: Value, args: &[QueryCmd], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_array(json, mode, |vs| {
+        Ok(keyed(vs, &args[0], mode)?
+            .into_iter()
+            .min_by(|(a, _), (b, _)| json_cmp(a, b))
+            .map_or(Value::Null, |(_, v)| v))
+    })
+}
+
+fn max_by(json: Value, args: &[QueryCmd], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_array(json, mode, |vs| {
+        Ok(keyed(vs, &args[0], mode)?
+            .into_iter()
+            .max_by(|(a, _), (b, _)| json_cmp(a, b))
+            .map_or(Value::Null, |(_, v)| v))
+    })
+}
+
+/// How many values `top(n; path)` keeps, a literal so it's known before the first record.
+fn top_count(args: &[QueryCmd]) -> Result<usize, String> {
+    match args.first() {
+        Some(QueryCmd::Literal(Number(n))) if n.is_u64() => Ok(n.as_u64().unwrap_or(0) as usize),
+        _ => Err(String::from(
+            "`top` takes how many values to keep first, as a whole number like `top(10; Clicks)`",
+        )),
+    }
+}
+
+fn check_top(args: &[QueryCmd]) -> Result<(), String> {
+    top_count(args).map(|_| ())
+}
+
+fn top(json: Value, args: &[QueryCmd], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_array(json, mode, |vs| fold(fold_top(args), vs, mode))
+}
+
+fn fold_top(args: &[QueryCmd]) -> Box<dyn Aggregator + '_> {
+    let n = top_count(args)
+        .unwrap_or_else(|e| unreachable!("`check_top` let an invalid count through: {}", e));
+    Box::new(TopN::new(n, &args[1]))
+}
+
+/// The percent of `percentile(p)`, a literal from 0 to 100.
+fn percent(args: &[QueryCmd]) -> Result<f64, String> {
+    match args.first() {
+        Some(QueryCmd::Literal(Number(n))) => n.as_f64().filter(|p| (0.0..=100.0).contains(p)),
+        _ => None,
+    }
+    .ok_or_else(|| String::from("`percentile` takes a number from 0 to 100, like `percentile(95)`"))
+}
+
+fn check_percentile(args: &[QueryCmd]) -> Result<(), String> {
+    percent(args).map(|_| ())
+}
+
+fn percentile(json: Value, args: &[QueryCmd], mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    on_array(json, mode, |vs| fold(fold_percentile(args), vs, mode))
+}
+
+fn fold_percentile(args: &[QueryCmd]) -> Box<dyn Aggregator + '_> {
+    let p = percent(args).unwrap_or_else(|e| {
+        unreachable!("`check_percentile` let an invalid percent through: {}", e)
+    });
+    Box::new(Percentile::new(p))
+}
+
+fn can_apply_streaming(cmd: &QueryCmd) -> bool {
+    match cmd {
+        QueryCmd::FilterCmd(_) => true,
+        QueryCmd::KeywordAccess(_) | QueryCmd::PathSearch(_) => true,
+        QueryCmd::Optional(_) | QueryCmd::Alternative(_) | QueryCmd::Literal(_) => true,
+        QueryCmd::TransformIntoObject(_) => true,
+        QueryCmd::ArrayIndexAccess(_) | QueryCmd::Slice(_) => true,
+        QueryCmd::FunctionCall(f, _) => !f.aggregates,
+        QueryCmd::Arithmetic(..) | QueryCmd::Conditional(..) => true,
+        // everything else either needs to accumlate state (SelectRecords) or terminates computation (keys, count, sum)
+        _ => false,
+    }
+}
+
+fn apply_cmd(v: Value, cmd: &QueryCmd, mode: EvalMode) -> Result<Option<Value>, JqrError> {
+    match cmd {
+        QueryCmd::FilterCmd(_) => apply_filter(v, cmd, mode),
+        QueryCmd::KeywordAccess(_) | QueryCmd::PathSearch(_) => eval(v, cmd, mode),
+        QueryCmd::Optional(_) | QueryCmd::Alternative(_) | QueryCmd::Literal(_) => {
+            eval(v, cmd, mode)
+        }
+        QueryCmd::TransformIntoObject(_) => eval(v, cmd, mode),
+        QueryCmd::ArrayIndexAccess(_) | QueryCmd::Slice(_) => eval(v, cmd, mode),
+        QueryCmd::FunctionCall(..) | QueryCmd::Arithmetic(..) | QueryCmd::Conditional(..) => {
+            eval(v, cmd, mode)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// What the tail of a `Pipeline` gets once the stream ends.
+enum Rows<'a> {
+    /// Every record that got through the head, for commands that need them all, like `.sort_by`.
+    All(Vec<Value>),
+    /// The tail starts with a function that aggregates records as they come, like `.count`.
+    Folded(Recovering<'a>),
+    /// The tail starts with `group_by(path) | { ... }`, it yields an object per group.
+    Grouped(Groups<'a>),
+}
+
+/// A query run over a stream of records. Its streamable head is applied to each record as it
+/// arrives, the rest, from the first command that needs all of them like `.count` on, runs once
+/// the stream ends. Aggregating functions fold records as they come, in constant memory, anything
+/// else gets an array of every record that got through.
+struct Pipeline<'a> {
+    head: &'a [QueryCmd],
+    tail: &'a [QueryCmd],
+    rows: Rows<'a>,
+}
+
+impl<'a> Pipeline<'a> {
+    fn new(cmds: &'a [QueryCmd]) -> Pipeline<'a> {
+        let split = cmds
+            .iter()
+            .position(|c| !can_apply_streaming(c))
+            .unwrap_or(cmds.len());
+        let (head, tail) = cmds.split_at(split);
+        let rows = match tail.first() {
+            Some(QueryCmd::FunctionCall(f, args)) => match f.aggregator(args) {
+                Some(agg) => Rows::Folded(Recovering::new(agg)),
+                None => Rows::All(vec![]),
+            },
+            Some(QueryCmd::GroupBy(grouping)) => Rows::Grouped(Groups::new(grouping)),
+            _ => Rows::All(vec![]),
+        };
+        Pipeline { head, tail, rows }
+    }
+
+    fn push(
+        &mut self,
+        json: Value,
+        mode: EvalMode,
+        out: &mut impl JsonWriter,
+    ) -> Result<(), JqrError> {
+        let mut jv = Some(json);
+        for cmd in self.head {
+            match jv {
+                Some(j) => jv = apply_cmd(j, cmd, mode)?,
+                // filtered out, none of the following commands need to see it
+                None => break,
+            }
+        }
+        match (jv, &mut self.rows) {
+            (None, _) => (),
+            (Some(jv), _) if self.tail.is_empty() => out.write_json(&jv)?,
+            (Some(jv), Rows::All(rows)) => rows.push(jv),
+            (Some(jv), Rows::Folded(agg)) => agg.push(jv, mode)?,
+            (Some(jv), Rows::Grouped(groups)) => groups.push(jv, mode)?,
+        }
+        Ok(())
+    }
+
+    fn finish(self, mode: EvalMode, out: &mut impl JsonWriter) -> Result<(), JqrError> {
+        let (results, rest) = match self.rows {
+            _ if self.tail.is_empty() => return Ok(()),
+            Rows::All(rows) => (vec![Value::Array(rows)], self.tail),
+            Rows::Folded(agg) => (vec![agg.finish()], &self.tail[1..]),
+            Rows::Grouped(groups) => (groups.into_objects(), &self.tail[1..]),
+        };
+        results
+            .into_iter()
+            .try_for_each(|jv| write_through(jv, rest, mode, out))
+    }
+}
+
+/// Runs `jv` through every one of `cmds` and writes what comes out at the end, if anything.
+fn write_through(
+    jv: Value,
+    cmds: &[QueryCmd],
+    mode: EvalMode,
+    out: &mut impl JsonWriter,
+) -> Result<(), JqrError> {
+    let mut jv = Some(jv);
+    for cmd in cmds {
+        if let Some(v) = jv {
+            jv = eval(v, cmd, mode)?;
+        }
+    }
+    if let Some(jv) = jv {
+        out.write_json(&jv)?;
+    }
+    Ok(())
+}
+
+/// Whether `cmds` starts right away with a command that aggregates the records as they come,
+/// like `.count` or `group_by(path) | { ... }`. With nothing in front of it to pick values out of
+/// a record, it aggregates the records themselves.
+fn folds_across(cmds: &[QueryCmd]) -> bool {
+    match cmds.first() {
+        Some(QueryCmd::GroupBy(_)) => true,
+        Some(QueryCmd::FunctionCall(f, _)) => f.folds(),
+        _ => false,
+    }
+}
+
+/// The records a `#[...]` selection picks from the input stream, whose length is only known
+/// once it ends.
+#[derive(Clone, Copy)]
+enum Positions<'a> {
+    Indices(&'a [i64]),
+    Slice(&'a Slice),
+}
+
+impl<'a> Positions<'a> {
+    fn of(cmd: &'a QueryCmd) -> Option<Positions<'a>> {
+        match cmd {
+            QueryCmd::SelectRecords(select) => match select.as_ref() {
+                QueryCmd::ArrayIndexAccess(idx) => Some(Positions::Indices(idx)),
+                QueryCmd::Slice(slice) => Some(Positions::Slice(slice)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// How many of the latest records have to be held back until the stream ends, because
+    /// whether they're selected depends on a position counted from the end.
+    fn lookbehind(&self) -> usize {
+        let from_end = |i: &i64| if *i < 0 { i.unsigned_abs() as usize } else { 0 };
+        match self {
+            Positions::Indices(idx) => idx.iter().map(from_end).max().unwrap_or(0),
+            Positions::Slice(s) => s
+                .start
+                .iter()
+                .chain(&s.end)
+                .map(from_end)
+                .max()
+                .unwrap_or(0),
+        }
+    }
+
+    /// Whether record `i` is selected from a stream known to go on for more than `lookbehind`
+    /// records after it, so it comes before every position counted from the end.
+    fn selects_early(&self, i: usize) -> bool {
+        match self {
+            Positions::Indices(idx) => idx.iter().any(|j| *j >= 0 && *j as usize == i),
+            Positions::Slice(s) => {
+                let start = match s.start {
+                    None => 0,
+                    Some(start) if start >= 0 => start as usize,
+                    Some(_) => return false,
+                };
+                let before_end = match s.end {
+                    Some(end) if end >= 0 => i < end as usize,
+                    _ => true,
+                };
+                i >= start && before_end && (i - start).is_multiple_of(s.step)
+            }
+        }
+    }
+
+    /// Whether record `i` is selected from a stream of `len` records.
+    fn selects(&self, i: usize, len: usize) -> bool {
+        match self {
+            Positions::Indices(idx) => idx.iter().any(|j| resolve_index(*j, len) == Some(i)),
+            Positions::Slice(s) => {
+                let (start, end) = s.bounds(len);
+                i >= start && i < end && (i - start).is_multiple_of(s.step)
+            }
+        }
+    }
+}
+
+/// Hands the records selected by `positions` to `f` in stream order. Only positions counted from
+/// the end make it hold records back, and then just as many as the furthest one reaches.
+fn select_positions(
+    json_iter: impl Iterator<Item = Result<Value, JqrError>>,
+    positions: Positions,
+    mut f: impl FnMut(Value) -> Result<(), JqrError>,
+) -> Result<(), JqrError> {
+    let lookbehind = positions.lookbehind();
+    let mut held: VecDeque<(usize, Value)> = VecDeque::new();
+    let mut len = 0;
+    for (i, jv) in json_iter.enumerate() {
+        held.push_back((i, jv?));
+        len = i + 1;
+        if held.len() > lookbehind {
+            if let Some((i, jv)) = held.pop_front() {
+                if positions.selects_early(i) {
+                    f(jv)?;
+                }
+            }
+        }
+    }
+    held.into_iter()
+        .filter(|(i, _)| positions.selects(*i, len))
+        .try_for_each(|(_, jv)| f(jv))
+}
+
+//out: &mut dyn io::Write,
+//https://stackoverflow.com/a/47606476
+fn streaming_eval(
+    mut json_iter: impl Iterator<Item = Result<Value, JqrError>>,
+    query: QueryCmd,
+    mode: EvalMode,
+    out: &mut impl JsonWriter,
+) -> Result<(), JqrError> {
+    match (&query, Positions::of(&query)) {
+        (_, Some(positions)) => {
+            select_positions(json_iter, positions, |jv| Ok(out.write_json(&jv)?))?
+        }
+        (f @ QueryCmd::FilterCmd(_), _) => json_iter.try_for_each(|json| {
+            if let Some(jv) = apply_filter(json?, f, mode)? {
+                out.write_json(&jv)?;
+            }
+            Ok::<(), JqrError>(())
+        })?,
+        (QueryCmd::MultiCmd(cmds), _) => match (&cmds[0], Positions::of(&cmds[0])) {
+            (_, Some(positions)) => {
+                let mut pipeline = Pipeline::new(&cmds[1..]);
+                select_positions(json_iter, positions, |json| pipeline.push(json, mode, out))?;
+                pipeline.finish(mode, out)?
+            }
+            (QueryCmd::FilterCmd(_), _) => {
+                let mut pipeline = Pipeline::new(cmds);
+                json_iter.try_for_each(|json| pipeline.push(json?, mode, out))?;
+                pipeline.finish(mode, out)?
+            }
+            _ if folds_across(cmds) => {
+                let mut pipeline = Pipeline::new(cmds);
+                json_iter.try_for_each(|json| pipeline.push(json?, mode, out))?;
+                pipeline.finish(mode, out)?
+            }
+
+            // every record goes through the whole pipeline on its own
+            _ => json_iter.try_for_each(|jv| {
+                if let Some(jv) = eval(jv?, &query, mode)? {
+                    out.write_json(&jv)?;
+                }
+                Ok::<(), JqrError>(())
+            })?,
+        },
+        (q, _) if folds_across(std::slice::from_ref(q)) => {
+            let mut pipeline = Pipeline::new(std::slice::from_ref(q));
+            json_iter.try_for_each(|json| pipeline.push(json?, mode, out))?;
+            pipeline.finish(mode, out)?
+        }
+        (q, _) => json_iter.try_for_each(|jv| {
+            if let Some(jv) = eval(jv?, q, mode)? {
+                out.write_json(&jv)?;
+            }
+            Ok::<(), JqrError>(())
+        })?,
+    }
+
+    Ok(())
+}
+
+pub fn eval_cmd(cmd: CmdArgs) -> Result<(), JqrError> {
+    if cmd.show_help {
+        print!("{}", cli::HELP);
+        return Ok(());
+    }
+    if cmd.show_version {
+        println!("jqr {}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+
+    let query = match &cmd.query_file {
+        Some(path) => Some(fs::read_to_string(path).map_err(|e| JqrError::io_at(path, e))?),
+        None => cmd.query.clone(),
+    };
+    let query = query.as_deref().map(parse_cmd).transpose()?;
+    let report: Box<dyn Write> = match &cmd.error_file {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stderr()),
+    };
+
+    let mut records = InputFiles::new(&cmd.input_files, cmd.on_error, report);
+    let mut output = cmd.output.clone();
+    let color = cmd
+        .color
+        .unwrap_or_else(|| std::env::var_os("NO_COLOR").is_none() && io::stdout().is_terminal());
+    if color {
+        output.colors = Some(Colors::from_env());
+    }
+    let mut printer = Printer::new(BufWriter::new(io::stdout().lock()), output);
+    let printed = match query {
+        Some(query) => streaming_eval(&mut records, query, cmd.mode, &mut printer),
+        None => records.try_for_each(|jv| Ok(printer.write_json(&jv?)?)),
+    }
+    .and_then(|_| Ok(printer.flush()?));
+    match printed {
+        // the reader went away, e.g. `jqr ... | head`, nobody is left to see more output
+        Err(JqrError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+        r => r?,
+    }
+    match (records.skipped(), records.skipped_lines()) {
+        (0, _) => {}
+        (skipped, 0) => eprintln!("jqr: skipped {} malformed record(s)", skipped),
+        (skipped, lines) => eprintln!(
+            "jqr: skipped {} malformed record(s) and {} line(s) following them",
+            skipped, lines
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod eval_test {
+    use super::*;
+    use serde_json::Value;
+
+    fn sample_json(i: i32) -> Value {
+        json!({ "i": i, "name": "John Doe", "Revenue": 3223.0, "Collections": 10 + i, "age": 3 + i})
+    }
+
+    /// Streams `records` through `query` the way the cli does, collecting every value it writes.
+    fn stream(
+        records: impl IntoIterator<Item = Value>,
+        query: &str,
+        mode: EvalMode,
+    ) -> Result<Vec<Value>, JqrError> {
+        let mut buffer: Vec<Value> = Vec::new();
+        let cmd = parse_cmd(query).expect("parse_cmd should not fail");
+        streaming_eval(records.into_iter().map(Ok), cmd, mode, &mut |jv: &Value| {
+            buffer.push(jv.to_owned())
+        })?;
+        Ok(buffer)
+    }
+
+    #[test]
+    fn eval_cmd_test() {
+        let query_cmd = "#[23..100] | age > 18 | {Idx := i; N := name; Rv := Revenue; C := Collections} | Rv > 1500.5 | C > 50 | Idx < 50";
+        let buffer = stream((1..100).map(sample_json), query_cmd, EvalMode::Lenient)
+            .expect("streaming_eval shouldn't throw errors");
+
+        assert_ne!(buffer.len(), 0);
+        assert_eq!(buffer.len(), 9); // because  C > 50 | Idx < 50
+
+        //TODO should really clean this up
+        let first_result = buffer[0].as_object().expect("should be json object");
+        //  assert_eq!(buffer[0], sample_json(1));
+        assert_eq!(
+            first_result.get("N").expect("N should not be empty"),
+            "John Doe"
+        );
+        assert!(
+            first_result
+                .get("Rv")
+                .expect("Rv should not be empty")
+                .as_f64()
+                .expect("Rv should by float64")
+                > 1500.5
+        );
+        assert!(
+            first_result
+                .get("C")
+                .expect("C should not be empty")
+                .as_i64()
+                .expect("C should by int64")
+                > 50
+        );
+        let value_index = first_result
+            .get("Idx")
+            .expect("Idx should not be empty")
+            .as_i64()
+            .expect("Idx should by int64");
+        assert!(23 <= value_index);
+        assert!(value_index < 100);
+    }
+
+    #[test]
+    fn truth_table_test() {
+        let json = r#" {
+        "i": 12,
+        "name":"John Doe",
+        "Revenue": 12}"#;
+
+        let truth_table = vec![
+            ("#[0]", json, 1, json),
+            ("#[9]", json, 10, json),
+            ("#[]", json, 1, ""),
+            // ("", json, 1, ""),
+            ("#[22]", json, 10, ""),
+        ];
+
+        let empty_json = json!("");
+        for (cmd, input, input_size, expected) in truth_table {
+            let records = (0..input_size).map(|_| serde_json::from_str(input).unwrap());
+            let buffer = stream(records, cmd, EvalMode::Lenient)
+                .expect("streaming_eval shouldn't throw errors");
+
+            let result = buffer.first().unwrap_or(&empty_json);
+
+            let expected: Value = serde_json::from_str(expected).unwrap_or(json!(""));
+            assert_eq!(result, &expected, "Expected: {}, got: {}", expected, result);
+        }
+    }
+
+    #[test]
+    fn multi_cmd_index_slicing_test() {
+        //TODO write a test to handle this, a streaming index slicing and then .count!
+        // let cmd = "[100..300] | name | .count";
+        let cmd = "#[100..300] | name ";
+        let input_size = 300;
+        let expected = 200;
+
+        let buffer = stream((0..input_size).map(sample_json), cmd, EvalMode::Lenient)
+            .expect("streaming_eval shouldn't throw errors");
+
+        let result = buffer.len();
+
+        assert_eq!(result, expected, "Expected: {}, got: {}", expected, result);
+    }
+
+    #[test]
+    fn multi_cmd_streaming_with_count_after_test() {
+        let cmd = "#[100..300] | name | .count";
+        let input_size = 300;
+        let expected = json!(200);
 
-        let json_iter = (0..input_size).map(|i| sample_json(i));
+        let buffer = stream((0..input_size).map(sample_json), cmd, EvalMode::Lenient)
+            .expect("streaming_eval shouldn't throw errors");
 
-        let mut buffer: Vec<Value> = Vec::new();
-        let value_collector = |jv: &Value| {
-            buffer.push(jv.to_owned());
+        let empty_json = &json!("");
+        let result = buffer.first().unwrap_or(empty_json);
+
+        assert_eq!(result, &expected, "Expected: {}, got: {}", expected, result);
+    }
+
+    #[test]
+    fn multi_cmd_streaming_starting_with_filter_with_count_after_test() {
+        let cmd = "i < 100 | name | .count";
+        let input_size = 300;
+        let expected = json!(100);
+
+        let buffer = stream((0..input_size).map(sample_json), cmd, EvalMode::Lenient)
+            .expect("streaming_eval shouldn't throw errors");
+
+        let empty_json = &json!("");
+        let result = buffer.first().unwrap_or(empty_json);
+
+        assert_eq!(result, &expected, "Expected: {}, got: {}", expected, result);
+    }
+
+    #[test]
+    fn eval_errors_test() {
+        let obj = json!({ "a": [1, 2, 3], "b": { "c": 7 } });
+        assert!(matches!(
+            eval(obj.clone(), &parse_cmd("[0]").unwrap(), EvalMode::Strict),
+            Err(JqrError::TypeMismatch {
+                expected: "array",
+                found: "object"
+            })
+        ));
+        assert!(matches!(
+            eval(
+                obj.clone(),
+                &parse_cmd("a | [5]").unwrap(),
+                EvalMode::Strict
+            ),
+            Err(JqrError::IndexOutOfBounds { index: 5, len: 3 })
+        ));
+        assert!(matches!(
+            eval(obj.clone(), &parse_cmd("b.d").unwrap(), EvalMode::Strict),
+            Err(JqrError::MissingKey(k)) if k == "b.d"
+        ));
+        assert!(matches!(
+            eval(obj.clone(), &parse_cmd("b.c.d").unwrap(), EvalMode::Strict),
+            Err(JqrError::TypeMismatch {
+                expected: "object",
+                found: "number"
+            })
+        ));
+        assert!(matches!(
+            eval(
+                obj.clone(),
+                &parse_cmd("b.c | .count").unwrap(),
+                EvalMode::Strict
+            ),
+            Err(JqrError::TypeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn lenient_eval_test() {
+        let obj = json!({ "a": [1, 2, 3], "b": { "c": 7 } });
+        let truth_table = vec![
+            ("[0]", json!(null)),
+            ("a | [5]", json!(null)),
+            ("a | [1,5]", json!([2, null])),
+            ("b.d", json!(null)),
+            ("b.c.d", json!(null)),
+            ("b.c | .count", json!(null)),
+            ("{ x := b.c; y := b.c.d }", json!({ "x": 7, "y": null })),
+        ];
+        for (cmd, expected) in truth_table {
+            let result = eval(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Lenient)
+                .expect("lenient eval should not fail");
+            assert_eq!(result, Some(expected), "cmd: {}", cmd);
+        }
+
+        let filter = parse_cmd("b.c > \"abc\"").unwrap();
+        assert_eq!(
+            apply_filter(obj.clone(), &filter, EvalMode::Lenient).unwrap(),
+            None
+        );
+        assert!(apply_filter(obj, &filter, EvalMode::Strict).is_err());
+    }
+
+    #[test]
+    fn boolean_filter_test() {
+        let obj = json!({ "Clicks": 3, "CTR": 0.05, "name": "ad" });
+        let truth_table = vec![
+            ("Clicks > 0 and CTR < 0.1", true),
+            ("Clicks > 5 and CTR < 0.1", false),
+            ("Clicks > 5 or CTR < 0.1", true),
+            ("Clicks > 5 or CTR > 0.1", false),
+            ("not Clicks > 5", true),
+            ("not (Clicks > 0 and name = \"ad\")", false),
+            ("(Clicks > 5 or name = \"ad\") and not CTR > 1", true),
+            ("missing = 1 or Clicks = 3", true),
+        ];
+        for (cmd, expected) in truth_table {
+            let result = apply_filter(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Lenient)
+                .expect("filter should not fail");
+            assert_eq!(result.is_some(), expected, "cmd: {}", cmd);
+        }
+
+        // the right side of `and`/`or` isn't evaluated once the left side decides, so it can't fail
+        let strict = |cmd| apply_filter(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Strict);
+        assert!(strict("Clicks = 3 or Clicks > \"abc\"").unwrap().is_some());
+        assert!(strict("Clicks = 4 and Clicks > \"abc\"").unwrap().is_none());
+        assert!(strict("Clicks = 3 and Clicks > \"abc\"").is_err());
+
+        let buffer = stream(
+            (0..100).map(sample_json),
+            "i < 10 or i > 95 and not i = 97 | i",
+            EvalMode::Lenient,
+        )
+        .expect("streaming_eval shouldn't throw errors");
+        let expected: Vec<Value> = (0..10).chain(vec![96, 98, 99]).map(|i| json!(i)).collect();
+        assert_eq!(buffer, expected);
+    }
+
+    #[test]
+    fn compare_ops_test() {
+        let obj =
+            json!({ "Clicks": 3, "CTR": 0.5, "LastDate": "2020-04-24T00:00:00Z", "tag": null });
+        let truth_table = vec![
+            ("Clicks != 3", false),
+            ("Clicks != 4", true),
+            ("Clicks >= 3", true),
+            ("Clicks <= 2", false),
+            ("CTR >= 0.5", true),
+            ("CTR <= 0.49", false),
+            ("Clicks = 3.0", true),
+            ("LastDate > \"2020-04-01\"", true),
+            ("LastDate < \"2020-04-01\"", false),
+            ("LastDate >= \"2020-04-24T00:00:00Z\"", true),
+            ("LastDate != \"2020\"", true),
+            ("tag = \"x\"", false),
+            ("tag != \"x\"", true),
+            ("Clicks = \"abc\"", false),
+            ("Clicks != \"abc\"", true),
+            ("tag > 1", false),
+        ];
+        for (cmd, expected) in truth_table {
+            let result = apply_filter(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Lenient)
+                .expect("filter should not fail");
+            assert_eq!(result.is_some(), expected, "cmd: {}", cmd);
+        }
+        assert!(matches!(
+            apply_filter(obj, &parse_cmd("tag > 1").unwrap(), EvalMode::Strict),
+            Err(JqrError::TypeMismatch {
+                expected: "number",
+                found: "null"
+            })
+        ));
+    }
+
+    #[test]
+    fn filter_literals_test() {
+        let obj = json!({ "active": true, "deleted": null, "delta": -7, "ratio": 1.5e-3, "path": "/a/b c@d.e" });
+        let truth_table = vec![
+            ("active = true", true),
+            ("active != false", true),
+            ("active = false", false),
+            ("deleted = null", true),
+            ("missing = null", true),
+            ("active = null", false),
+            ("delta < -5", true),
+            ("delta >= -7", true),
+            ("delta = -7.0", true),
+            ("ratio < 1e-2", true),
+            ("ratio = 0.0015", true),
+            ("path = \"/a/b c@d.e\"", true),
+            ("path = \"\\/a\\/b c\\u0040d.e\"", true),
+            ("delta = \"-7\"", false),
+            ("active = \"true\"", false),
+            ("active > false", false),
+        ];
+        for (cmd, expected) in truth_table {
+            let result = apply_filter(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Lenient)
+                .expect("filter should not fail");
+            assert_eq!(result.is_some(), expected, "cmd: {}", cmd);
+        }
+        assert!(matches!(
+            apply_filter(obj, &parse_cmd("active > false").unwrap(), EvalMode::Strict),
+            Err(JqrError::TypeMismatch {
+                expected: "number or string",
+                found: "boolean"
+            })
+        ));
+    }
+
+    #[test]
+    fn field_comparison_test() {
+        let obj = json!({
+            "Clicks": 30,
+            "Impressions": 200,
+            "created_at": "2020-01-02",
+            "updated_at": "2020-03-04",
+            "owner": "ann",
+            "author": { "login": "ann" },
+            "done": true
+        });
+        let truth_table = vec![
+            ("Clicks < Impressions", true),
+            ("Clicks > Impressions", false),
+            ("updated_at > created_at", true),
+            ("owner = author.login", true),
+            ("owner != author.login", false),
+            ("Clicks * 10 > Impressions", true),
+            ("Clicks / Impressions >= 0.15", true),
+            ("Impressions - Clicks * 6 < Clicks", true),
+            ("done = Clicks", false),
+            ("done != Clicks", true),
+            ("Clicks = missing?", false),
+            ("Clicks != missing?", true),
+            ("missing? = missing?", false),
+            ("owner = \"ann\" and created_at < updated_at", true),
+        ];
+        for (cmd, expected) in truth_table {
+            let result = apply_filter(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Strict)
+                .expect("filter should not fail");
+            assert_eq!(result.is_some(), expected, "cmd: {}", cmd);
+        }
+        assert!(matches!(
+            apply_filter(obj, &parse_cmd("Clicks > owner").unwrap(), EvalMode::Strict),
+            Err(JqrError::TypeMismatch {
+                expected: "string",
+                found: "number"
+            })
+        ));
+
+        // comparing two fields doesn't keep a filter from streaming
+        assert!(can_apply_streaming(
+            &parse_cmd("Collections > age * 2").unwrap()
+        ));
+        let buffer = stream(
+            (0..10).map(sample_json),
+            "Collections > age * 2 | i",
+            EvalMode::Strict,
+        )
+        .expect("streaming_eval should not fail");
+        assert_eq!(buffer, vec![json!(0), json!(1), json!(2), json!(3)]);
+    }
+
+    #[test]
+    fn special_keys_test() {
+        let obj = json!({
+            "node_id": 1,
+            "headers": { "Content-Type": "text/html", "@timestamp": "2020-05-09" },
+            "größe": { "wert": 7 },
+            "weird key": [1, 2]
+        });
+        let truth_table = vec![
+            ("node_id", json!(1)),
+            ("headers.\"Content-Type\"", json!("text/html")),
+            ("headers[\"@timestamp\"]", json!("2020-05-09")),
+            ("größe.wert", json!(7)),
+            ("[\"weird key\"] | .count", json!(2)),
+        ];
+        for (cmd, expected) in truth_table {
+            let result = eval(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Strict)
+                .expect("eval should not fail");
+            assert_eq!(result, Some(expected), "cmd: {}", cmd);
+        }
+    }
+
+    #[test]
+    fn array_slice_test() {
+        let obj = json!({ "a": [0, 1, 2, 3, 4, 5, 6, 7, 8, 9] });
+        let truth_table = vec![
+            ("a | [-1]", json!(9)),
+            ("a | [0, -2]", json!([0, 8])),
+            ("a | [7..]", json!([7, 8, 9])),
+            ("a | [..3]", json!([0, 1, 2])),
+            ("a | [-3..]", json!([7, 8, 9])),
+            ("a | [2..-5]", json!([2, 3, 4])),
+            ("a | [::3]", json!([0, 3, 6, 9])),
+            ("a | [1:8:2]", json!([1, 3, 5, 7])),
+            ("a | [5..100]", json!([5, 6, 7, 8, 9])),
+            ("a | [8..2]", json!([])),
+            ("a | [-20]", json!(null)),
+        ];
+        for (cmd, expected) in truth_table {
+            let result = eval(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Lenient)
+                .expect("eval should not fail");
+            assert_eq!(result, Some(expected), "cmd: {}", cmd);
+        }
+        assert!(matches!(
+            eval(obj, &parse_cmd("a | [-11]").unwrap(), EvalMode::Strict),
+            Err(JqrError::IndexOutOfBounds {
+                index: -11,
+                len: 10
+            })
+        ));
+    }
+
+    #[test]
+    fn stream_positions_test() {
+        let truth_table: Vec<(&str, Vec<i32>)> = vec![
+            ("[-1]", vec![9]),
+            ("[-1, 0, -3]", vec![0, 7, 9]),
+            ("[7..]", vec![7, 8, 9]),
+            ("[-3..]", vec![7, 8, 9]),
+            ("[..-7]", vec![0, 1, 2]),
+            ("[2..-5]", vec![2, 3, 4]),
+            ("[-4..-2]", vec![6, 7]),
+            ("[::4]", vec![0, 4, 8]),
+            ("[-5::2]", vec![5, 7, 9]),
+            ("[0..100000000]", (0..10).collect()),
+            ("[-20]", vec![]),
+        ];
+        for (cmd, expected) in truth_table {
+            let buffer = stream(
+                (0..10).map(sample_json),
+                &format!("#{} | i", cmd),
+                EvalMode::Lenient,
+            )
+            .expect("streaming_eval shouldn't throw errors");
+            let expected: Vec<Value> = expected.into_iter().map(|i| json!(i)).collect();
+            assert_eq!(buffer, expected, "cmd: {}", cmd);
+        }
+    }
+
+    #[test]
+    fn index_semantics_test() {
+        let records = vec![json!(["a", "b", "c"]), json!(["d", "e"]), json!(["f"])];
+        let run = |cmd: &str| {
+            stream(records.clone(), cmd, EvalMode::Lenient)
+                .expect("streaming_eval shouldn't throw errors")
+        };
+
+        // plain index access looks into every record, in the stream and in a pipeline alike
+        assert_eq!(run("[0]"), vec![json!("a"), json!("d"), json!("f")]);
+        assert_eq!(
+            run("[0,1]"),
+            vec![json!(["a", "b"]), json!(["d", "e"]), json!(["f", null])]
+        );
+        assert_eq!(
+            run("[1..]"),
+            vec![json!(["b", "c"]), json!(["e"]), json!([])]
+        );
+        // and an aggregate after it runs on what it yields for each record, unless `#[...]` or a
+        // filter in front makes it take in the whole stream
+        assert_eq!(run("[1..] | .count"), vec![json!(2), json!(1), json!(0)]);
+        assert_eq!(run("[-1] | .count"), vec![json!(null); 3]);
+        assert_eq!(run("#[..] | [-1] | .count"), vec![json!(3)]);
+        // `#[...]` picks whole records by their position in the stream
+        assert_eq!(run("#[0,2]"), vec![json!(["a", "b", "c"]), json!(["f"])]);
+        assert_eq!(run("#[1] | [0]"), vec![json!("d")]);
+        assert_eq!(
+            run("#[..2] | [0,2]"),
+            vec![json!(["a", "c"]), json!(["d", null])]
+        );
+        assert_eq!(run("#[1..] | .count"), vec![json!(2)]);
+
+        // a single document is a stream of one record
+        let select = parse_cmd("#[0]").unwrap();
+        assert_eq!(
+            eval(json!([1]), &select, EvalMode::Lenient).unwrap(),
+            Some(json!([1]))
+        );
+        let select = parse_cmd("#[1..]").unwrap();
+        assert_eq!(eval(json!([1]), &select, EvalMode::Lenient).unwrap(), None);
+    }
+
+    #[test]
+    fn path_search_test() {
+        let doc = json!({
+            "id": 1,
+            "wrapper": { "payload": { "id": 2, "tags": ["a", "b"] } },
+            "items": [{ "id": 3 }, { "name": "x" }, { "id": { "id": 4 } }]
+        });
+        let truth_table = vec![
+            ("..id", json!([1, 3, { "id": 4 }, 4, 2])),
+            ("wrapper..id", json!([2])),
+            ("items.*.id", json!([3, { "id": 4 }])),
+            ("wrapper.*.tags", json!([["a", "b"]])),
+            ("wrapper.payload.tags.*", json!(["a", "b"])),
+            ("items.**.name", json!(["x"])),
+            (
+                "wrapper.**",
+                json!([
+                    { "payload": { "id": 2, "tags": ["a", "b"] } },
+                    { "id": 2, "tags": ["a", "b"] },
+                    2,
+                    ["a", "b"],
+                    "a",
+                    "b"
+                ]),
+            ),
+            ("..missing", json!([])),
+            ("id.*", json!([])),
+            ("..id | .count", json!(5)),
+        ];
+        for (cmd, expected) in truth_table {
+            let result = eval(doc.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Strict)
+                .expect("path search should not fail");
+            assert_eq!(result, Some(expected), "cmd: {}", cmd);
+        }
+
+        // in the streaming prefix each record is searched on its own
+        let buffer = stream(
+            vec![doc.clone(), json!({ "x": { "id": 9 } })],
+            "id > 0 or x.id > 0 | ..id | [0]",
+            EvalMode::Lenient,
+        )
+        .expect("streaming_eval shouldn't throw errors");
+        assert_eq!(buffer, vec![json!(1), json!(9)]);
+    }
+
+    #[test]
+    fn optional_and_default_test() {
+        let obj =
+            json!({ "a": { "b": null, "c": 0 }, "n": 5, "list": [{ "x": 1 }, {}, { "x": null }] });
+        let truth_table = vec![
+            ("a.b?", Some(json!(null))),
+            ("a.missing?", None),
+            ("n.deeper?", None),
+            ("list | x?", Some(json!([1, null]))),
+            ("a.b // 1", Some(json!(1))),
+            ("a.missing // a.c", Some(json!(0))),
+            ("a.missing? // \"N/A\"", Some(json!("N/A"))),
+            ("a.missing // a.b // false", Some(json!(false))),
+            ("n // 1", Some(json!(5))),
+            (
+                "{ b := a.b?; m := a.missing?; d := a.missing // 0 }",
+                Some(json!({ "b": null, "d": 0 })),
+            ),
+            (
+                "{ b := a.b; m := a.missing }",
+                Some(json!({ "b": null, "m": null })),
+            ),
+        ];
+        for (cmd, expected) in truth_table {
+            let result = eval(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Lenient)
+                .expect("eval should not fail");
+            assert_eq!(result, expected, "cmd: {}", cmd);
+        }
+
+        // neither `?` nor the left side of `//` fail in strict mode, a plain missing key still does
+        let strict = |cmd| eval(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Strict);
+        assert_eq!(strict("a.missing?").unwrap(), None);
+        assert_eq!(strict("a.missing // 1").unwrap(), Some(json!(1)));
+        assert!(matches!(strict("a.missing"), Err(JqrError::MissingKey(_))));
+        assert!(matches!(
+            strict("a.b // a.missing"),
+            Err(JqrError::MissingKey(_))
+        ));
+        // nor does anything else along the way that isn't there
+        assert_eq!(strict("n.deeper // 1").unwrap(), Some(json!(1)));
+        assert_eq!(strict("list | [5]?").unwrap(), None);
+        assert_eq!(
+            strict("list | [5]? // \"none\"").unwrap(),
+            Some(json!("none"))
+        );
+        assert_eq!(
+            strict("list | [-2..]?").unwrap(),
+            Some(json!([{}, { "x": null }]))
+        );
+        assert_eq!(strict("n | [0]?").unwrap(), None);
+        assert_eq!(strict("a | [0] // 1").unwrap(), Some(json!(1)));
+        assert!(matches!(
+            strict("list | [5]"),
+            Err(JqrError::IndexOutOfBounds { .. })
+        ));
+
+        // filters tell a missing key from one set to null
+        let filter = |cmd| {
+            apply_filter(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Lenient)
+                .unwrap()
+                .is_some()
+        };
+        assert!(filter("a.b? = null"));
+        assert!(!filter("a.missing? = null"));
+        assert!(filter("a.missing? != null"));
+        assert!(filter("a.missing = null"));
+        assert!(filter("a.missing // 3 > 2"));
+    }
+
+    #[test]
+    fn builtins_test() {
+        let obj = json!({
+            "name": "Zoë",
+            "n": -3,
+            "tags": ["b", "a", "b"],
+            "nums": [3, 1.5, 2],
+            "ints": [1, 2, 3],
+            "nested": [1, [2, [3, []]]],
+            "mixed": [{ "a": 1 }, "x", null, 2, [1], true],
+            "o": { "b": 1, "a": { "c": [1, 2] } },
+            "num": "42.5",
+            "none": null
+        });
+        let truth_table = vec![
+            ("name | .length", json!(3)),
+            ("tags | .length", json!(3)),
+            ("o | length()", json!(2)),
+            ("n | .length", json!(3)),
+            ("none | .length", json!(0)),
+            ("n | .type", json!("number")),
+            ("none | .type", json!("null")),
+            ("o | .type", json!("object")),
+            ("has(\"name\")", json!(true)),
+            ("has(\"nope\")", json!(false)),
+            ("tags | has(2)", json!(true)),
+            ("tags | has(3)", json!(false)),
+            ("name | contains(\"oë\")", json!(true)),
+            ("tags | contains([1..])", json!(true)),
+            ("o | contains({ a := a })", json!(true)),
+            ("o | contains({ b := a })", json!(false)),
+            ("tags | .first", json!("b")),
+            ("tags | .last", json!("b")),
+            ("tags | [5..] | .first", json!(null)),
+            ("tags | .reverse", json!(["b", "a", "b"])),
+            ("name | .reverse", json!("ëoZ")),
+            ("tags | .unique", json!(["a", "b"])),
+            (
+                "mixed | .unique",
+                json!([null, true, 2, "x", [1], { "a": 1 }]),
+            ),
+            ("nested | .flatten", json!([1, 2, 3])),
+            ("nums | .min", json!(1.5)),
+            ("nums | .max", json!(3)),
+            ("tags | .max", json!("b")),
+            ("ints | .sum", json!(6)),
+            ("nums | .sum", json!(6.5)),
+            ("ints | .avg", json!(2.0)),
+            ("tags | [5..] | .avg", json!(null)),
+            ("tags | [5..] | .sum", json!(0)),
+            ("n | .tostring", json!("-3")),
+            ("tags | .tostring", json!("[\"b\",\"a\",\"b\"]")),
+            ("name | .tostring", json!("Zoë")),
+            ("num | .tonumber", json!(42.5)),
+            ("n | .tonumber", json!(-3)),
+            ("o | .keys", json!(["a", "b"])),
+            ("o | .keys_sorted", json!(["a", "b"])),
+            ("tags | .keys", json!([0, 1, 2])),
+            ("o | .vals | .count", json!(2)),
+            ("ints | .sum // 0", json!(6)),
+        ];
+        for (cmd, expected) in truth_table {
+            let result = eval(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Strict)
+                .expect("eval should not fail");
+            assert_eq!(result, Some(expected), "cmd: {}", cmd);
+        }
+
+        let strict = |cmd| eval(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Strict);
+        let lenient = |cmd| eval(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Lenient);
+        for cmd in &[
+            "name | .tonumber",
+            "mixed | .sum",
+            "name | .first",
+            "name | contains(1)",
+            "o | has(0)",
+        ] {
+            assert!(
+                matches!(strict(cmd), Err(JqrError::TypeMismatch { .. })),
+                "cmd: {}",
+                cmd
+            );
+            assert_eq!(lenient(cmd).unwrap(), Some(json!(null)), "cmd: {}", cmd);
+        }
+
+        let filter = |cmd| {
+            apply_filter(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Lenient)
+                .unwrap()
+                .is_some()
         };
-        let parse_res = parse_cmd(&cmd.to_string());
-        let cmd = parse_res.expect("parse_cmd should not fail");
-        streaming_eval(json_iter, cmd, value_collector)
+        assert!(filter("has(\"tags\") = true and .length > 5"));
+        assert!(filter("not .type = \"array\""));
+        assert!(!filter("has(\"x\") = true"));
+    }
+
+    #[test]
+    fn string_functions_test() {
+        let obj = json!({
+            "login": "JDoe",
+            "email": " jane.doe@example.com\t",
+            "tags": ["a", 1, null, true],
+            "path": "/api/v1/users",
+            "n": 7
+        });
+        let truth_table =
+            vec![
+            ("login | .ascii_downcase", json!("jdoe")),
+            ("login | .ascii_upcase", json!("JDOE")),
+            ("email | .trim | split(\"@\")", json!(["jane.doe", "example.com"])),
+            ("login | split(\"\")", json!(["J", "D", "o", "e"])),
+            ("tags | join(\"-\")", json!("a-1--true")),
+            ("path | ltrimstr(\"/api\")", json!("/v1/users")),
+            ("path | ltrimstr(\"api\")", json!("/api/v1/users")),
+            ("path | rtrimstr(\"/users\")", json!("/api/v1")),
+            ("path | startswith(\"/api/\")", json!(true)),
+            ("path | endswith(\"/api\")", json!(false)),
+            ("path | substr(1; 4)", json!("api")),
+            ("path | substr(-5, 100)", json!("users")),
+            ("path | test(\"v[0-9]+\")", json!(true)),
+            ("login | test(\"^jd\")", json!(false)),
+            ("nick? // login | .ascii_downcase", json!("jdoe")),
+            (
+                "{ user := login | .ascii_downcase; domain := email | .trim | split(\"@\") | [1] }",
+                json!({ "user": "jdoe", "domain": "example.com" }),
+            ),
+        ];
+        for (cmd, expected) in truth_table {
+            let result = eval(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Strict)
+                .expect("eval should not fail");
+            assert_eq!(result, Some(expected), "cmd: {}", cmd);
+        }
+
+        let eval_with = |cmd, mode| eval(obj.clone(), &parse_cmd(cmd).unwrap(), mode);
+        assert_eq!(
+            eval_with("login | .ascii_downcase = \"jdoe\"", EvalMode::Strict).unwrap(),
+            Some(json!("JDoe"))
+        );
+        assert_eq!(
+            eval_with("path | startswith(\"/v1\") = true", EvalMode::Strict).unwrap(),
+            None
+        );
+        assert!(matches!(
+            eval_with("n | .ascii_upcase", EvalMode::Strict),
+            Err(JqrError::TypeMismatch { .. })
+        ));
+        assert_eq!(
+            eval_with("login | substr(0; \"x\")", EvalMode::Lenient).unwrap(),
+            Some(json!(null))
+        );
+    }
+
+    #[test]
+    fn regex_test() {
+        let obj = json!({
+            "msg": "GET /users/42 took 17ms, GET /users/7 took 3ms",
+            "level": "WARN",
+            "n": 3
+        });
+        let truth_table = vec![
+            ("msg | test(\"took [0-9]+ms\")", Some(json!(true))),
+            (
+                "msg | capture(\"(?P<method>[A-Z]+) /users/(?P<id>\\\\d+)(?P<q>\\\\?)?\")",
+                Some(json!({ "method": "GET", "id": "42", "q": null })),
+            ),
+            ("msg | capture(\"POST\")", None),
+            ("msg | scan(\"[0-9]+ms\")", Some(json!(["17ms", "3ms"]))),
+            (
+                "msg | scan(\"/(\\\\w+)/(\\\\d+)\")",
+                Some(json!([["users", "42"], ["users", "7"]])),
+            ),
+            (
+                "msg | sub(\"/users/(?P<id>\\\\d+)\"; \"/u/${id}\")",
+                Some(json!("GET /u/42 took 17ms, GET /users/7 took 3ms")),
+            ),
+            (
+                "msg | gsub(\"[0-9]+ms\", \"?\")",
+                Some(json!("GET /users/42 took ?, GET /users/7 took ?")),
+            ),
+            (
+                "{ id := msg | capture(\"/users/(?P<id>\\\\d+)\") | id; none := msg | capture(\"x\") }",
+                Some(json!({ "id": "42" })),
+            ),
+        ];
+        for (cmd, expected) in truth_table {
+            let result = eval(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Strict)
+                .expect("eval should not fail");
+            assert_eq!(result, expected, "cmd: {}", cmd);
+        }
+
+        let filter = |cmd, mode| {
+            apply_filter(obj.clone(), &parse_cmd(cmd).unwrap(), mode).map(|r| r.is_some())
+        };
+        assert!(filter("msg ~ \"^GET\"", EvalMode::Strict).unwrap());
+        assert!(filter(
+            "level matches \"WARN|ERROR\" and not msg ~ \"POST\"",
+            EvalMode::Strict
+        )
+        .unwrap());
+        assert!(!filter("missing? ~ \".*\"", EvalMode::Strict).unwrap());
+        assert!(!filter("n ~ \"3\"", EvalMode::Lenient).unwrap());
+        assert!(filter("n ~ \"3\"", EvalMode::Strict).is_err());
+
+        // patterns are compiled with the query, so a broken one fails before any input is read
+        assert!(matches!(
+            parse_cmd("msg ~ \"(\""),
+            Err(JqrError::Parse { .. })
+        ));
+    }
+
+    #[test]
+    fn streaming_functions_test() {
+        let run = |query: &str| {
+            stream((0..100).map(sample_json), query, EvalMode::Lenient)
+                .expect("streaming_eval shouldn't throw errors")
+        };
+
+        // array functions get every record that got through, others each one on its own
+        assert_eq!(run("i > 95 | i | .sum"), vec![json!(96 + 97 + 98 + 99)]);
+        assert_eq!(run("#[10..20] | age | .max"), vec![json!(22)]);
+        assert_eq!(run("#[0..3] | .first | i"), vec![json!(0)]);
+        assert_eq!(
+            run("i > 97 | i | .tostring"),
+            vec![json!("98"), json!("99")]
+        );
+        assert_eq!(
+            run("#[-2..] | { t := .type; k := .count }"),
+            vec![
+                json!({ "t": "object", "k": 5 }),
+                json!({ "t": "object", "k": 5 })
+            ]
+        );
+        assert_eq!(
+            run("i < 2 | has(\"age\") = true | i"),
+            vec![json!(0), json!(1)]
+        );
+    }
+
+    #[test]
+    fn sort_functions_test() {
+        let obj = json!({
+            "rows": [
+                { "c": "a", "n": 5 },
+                { "c": "b", "n": 9 },
+                { "c": "c" },
+                { "c": "d", "n": 9 },
+                { "c": "e", "n": 3 }
+            ],
+            "none": null
+        });
+        let names = |cmd: &str| {
+            let cmd = format!("rows | {} | ..c", cmd);
+            eval(obj.clone(), &parse_cmd(&cmd).unwrap(), EvalMode::Lenient)
+                .expect("eval should not fail")
+        };
+        // a missing key sorts as null, first of all, ties keep their order
+        assert_eq!(names("sort_by(n)"), Some(json!(["c", "e", "a", "b", "d"])));
+        assert_eq!(
+            names("sort_by(n; desc)"),
+            Some(json!(["b", "d", "a", "e", "c"]))
+        );
+        assert_eq!(names("sort_by(n, asc)"), names("sort_by(n)"));
+        assert_eq!(names("unique_by(n)"), Some(json!(["c", "e", "a", "b"])));
+        assert_eq!(names("top(3; n)"), Some(json!(["b", "d", "a"])));
+        assert_eq!(names("top(0; n)"), Some(json!([])));
+        assert_eq!(names("top(9; c)"), Some(json!(["e", "d", "c", "b", "a"])));
+        assert_eq!(names("top(18446744073709551615; c)"), names("top(9; c)"));
+
+        let run = |cmd: &str| {
+            eval(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Lenient)
+                .expect("eval should not fail")
+        };
+        assert_eq!(run("rows | min_by(n) | c"), Some(json!("c")));
+        assert_eq!(run("rows | max_by(n) | c"), Some(json!("d")));
+        assert_eq!(run("rows | [5..] | max_by(n)"), Some(json!(null)));
+        assert_eq!(run("rows | group_by(n) | .count"), Some(json!(4)),);
+        assert_eq!(
+            run("rows | group_by(n) | [-1] | ..c"),
+            Some(json!(["b", "d"]))
+        );
+        assert_eq!(run("none | sort_by(n)"), Some(json!(null)));
+        assert!(matches!(
+            eval(
+                obj.clone(),
+                &parse_cmd("sort_by(n)").unwrap(),
+                EvalMode::Strict
+            ),
+            Err(JqrError::TypeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn streaming_top_test() {
+        let run = |query: &str| {
+            stream((0..10_000).map(sample_json), query, EvalMode::Lenient)
+                .expect("streaming_eval shouldn't throw errors")
+        };
+
+        assert_eq!(
+            run("i >= 0 | top(3; age) | ..i"),
+            vec![json!([9999, 9998, 9997])]
+        );
+        assert_eq!(
+            run("#[..100] | { i := i; r := Revenue } | top(2; r) | ..i"),
+            vec![json!([0, 1])]
+        );
+        assert_eq!(run("i < 0 | top(3; age)"), vec![json!([])]);
+        assert_eq!(
+            run("#[-3..] | sort_by(i; desc) | ..i"),
+            vec![json!([9999, 9998, 9997])]
+        );
+    }
+
+    #[test]
+    fn streaming_aggregates_test() {
+        let run = |query: &str, mode: EvalMode| stream((0..1000).map(sample_json), query, mode);
+        let lenient = |query: &str| run(query, EvalMode::Lenient).expect("should not fail");
+
+        assert_eq!(lenient("i < 100 | name | .count"), vec![json!(100)]);
+        assert_eq!(lenient("i < 0 | .count"), vec![json!(0)]);
+        assert_eq!(lenient("i < 0 | age | .avg"), vec![json!(null)]);
+        assert_eq!(lenient("#[10..20] | i | .sum"), vec![json!(145)]);
+        assert_eq!(lenient("#[..4] | age | .avg"), vec![json!(4.5)]);
+        assert_eq!(lenient("i >= 0 | name | .min"), vec![json!("John Doe")]);
+        assert_eq!(lenient("i >= 0 | age | .max"), vec![json!(1002)]);
+        assert_eq!(lenient("i >= 0 | name | .count_distinct"), vec![json!(1)]);
+        let distinct = lenient("i >= 0 | i | .count_distinct")[0].as_f64().unwrap();
+        assert!((distinct - 1000.0).abs() < 10.0, "got {}", distinct);
+        assert_eq!(lenient("#[..5] | i | percentile(50)"), vec![json!(2.0)]);
+        // the aggregate's result goes through the rest of the query
+        assert_eq!(lenient("i < 10 | i | .sum | .tostring"), vec![json!("45")]);
+
+        // a value an aggregate can't take makes it null, or fails the query with --strict
+        assert_eq!(lenient("i >= 0 | name | .sum"), vec![json!(null)]);
+        assert!(matches!(
+            run("i >= 0 | name | .sum", EvalMode::Strict),
+            Err(JqrError::TypeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn streaming_group_by_test() {
+        let records = vec![
+            json!({ "AdTypeId": 2, "Clicks": 5, "Campaign": "a" }),
+            json!({ "AdTypeId": 1, "Clicks": 9, "Campaign": "b" }),
+            json!({ "AdTypeId": 2, "Clicks": 1, "Campaign": "c" }),
+            json!({ "AdTypeId": 1, "Campaign": "d" }),
+            json!({ "AdTypeId": 3, "Clicks": "n/a", "Campaign": "e" }),
+        ];
+        let run = |query: &str, mode: EvalMode| stream(records.clone(), query, mode);
+        let lenient = |query: &str| run(query, EvalMode::Lenient).expect("should not fail");
+
+        // one object per group, in the order of their keys
+        assert_eq!(
+            lenient(
+                "#[..] | group_by(AdTypeId) | { k := .key; clicks := sum(Clicks?); n := count; \
+                 best := top(1; Clicks?; Campaign) }"
+            ),
+            vec![
+                json!({ "k": 1, "clicks": 9, "n": 2, "best": ["b"] }),
+                json!({ "k": 2, "clicks": 6, "n": 2, "best": ["a"] }),
+                json!({ "k": 3, "clicks": null, "n": 1, "best": ["e"] }),
+            ]
+        );
+        // without `#[..]` or a filter in front it still groups the whole stream
+        assert_eq!(
+            lenient("group_by(AdTypeId) | { k := .key; clicks := sum(Clicks?); n := count }"),
+            vec![
+                json!({ "k": 1, "clicks": 9, "n": 2 }),
+                json!({ "k": 2, "clicks": 6, "n": 2 }),
+                json!({ "k": 3, "clicks": null, "n": 1 }),
+            ]
+        );
+        // and so does an aggregate at the start of a query, whatever the records hold
+        assert_eq!(lenient(".count"), vec![json!(5)]);
+        assert_eq!(lenient(".count | .tostring"), vec![json!("5")]);
+        assert_eq!(
+            lenient(".min"),
+            vec![json!({ "AdTypeId": 1, "Campaign": "d" })]
+        );
+        // while after a path it takes each record on its own
+        assert_eq!(lenient("Campaign | .count_distinct"), vec![json!(null); 5]);
+        assert_eq!(
+            lenient("#[..] | Campaign | .count_distinct"),
+            vec![json!(5)]
+        );
+        assert_eq!(lenient("#[..] | Clicks? | .sum"), vec![json!(null)]);
+        // the rest of the query runs on each group
+        assert_eq!(
+            lenient("Clicks? > 0 | group_by(AdTypeId) | { k := .key; n := count } | n > 1 | k"),
+            vec![json!(2)]
+        );
+        assert_eq!(
+            lenient("Clicks? > 100 | group_by(AdTypeId) | { n := count }"),
+            Vec::<Value>::new()
+        );
+        assert!(matches!(
+            run(
+                "#[..] | group_by(AdTypeId) | { clicks := sum(Clicks?) }",
+                EvalMode::Strict
+            ),
+            Err(JqrError::TypeMismatch { .. })
+        ));
+
+        // arrays in the stream are records like any other, `[..]` in front groups each one
+        let arrays = vec![
+            Value::Array(records[..2].to_vec()),
+            Value::Array(records[2..].to_vec()),
+        ];
+        let grouped = |query: &str| stream(arrays.clone(), query, EvalMode::Lenient).unwrap();
+        assert_eq!(
+            grouped("group_by(AdTypeId) | { k := .key; n := count }"),
+            vec![
+                json!({ "k": [2, 1], "n": 1 }),
+                json!({ "k": [2, 1, 3], "n": 1 })
+            ]
+        );
+        assert_eq!(
+            grouped("[..] | group_by(AdTypeId) | { k := .key; n := count }"),
+            vec![
+                json!([{ "k": 1, "n": 1 }, { "k": 2, "n": 1 }]),
+                json!([{ "k": 1, "n": 1 }, { "k": 2, "n": 1 }, { "k": 3, "n": 1 }]),
+            ]
+        );
+
+        // on an array it yields an array of the group objects
+        let result = eval(
+            Value::Array(records.clone()),
+            &parse_cmd("group_by(AdTypeId) | { k := .key; c := count_distinct(Campaign) }")
+                .unwrap(),
+            EvalMode::Lenient,
+        )
+        .unwrap();
+        assert_eq!(
+            result,
+            Some(json!([{ "k": 1, "c": 2 }, { "k": 2, "c": 2 }, { "k": 3, "c": 1 }]))
+        );
+
+        // numbers that compare equal share a group, streaming or not, like in `.group_by(path)`
+        let numbers = vec![
+            json!({ "k": 1 }),
+            json!({ "k": 1.0 }),
+            json!({ "k": 0 }),
+            json!({ "k": -0.0 }),
+        ];
+        let query = "group_by(k) | { k := .key; n := count }";
+        let expected = vec![json!({ "k": 0, "n": 2 }), json!({ "k": 1, "n": 2 })];
+        assert_eq!(
+            stream(numbers.clone(), query, EvalMode::Strict).unwrap(),
+            expected
+        );
+        assert_eq!(
+            eval(
+                Value::Array(numbers.clone()),
+                &parse_cmd(query).unwrap(),
+                EvalMode::Strict
+            )
+            .unwrap(),
+            Some(Value::Array(expected))
+        );
+        assert_eq!(
+            eval(
+                Value::Array(numbers),
+                &parse_cmd("group_by(k) | .count").unwrap(),
+                EvalMode::Strict
+            )
+            .unwrap(),
+            Some(json!(2))
+        );
+    }
+
+    #[test]
+    fn arithmetic_test() {
+        let obj = json!({
+            "clicks": 5,
+            "views": 200,
+            "rate": 1.5,
+            "big": i64::MAX,
+            "first": "Jo",
+            "last": "Doe",
+            "tags": ["a", "b"],
+            "more": ["b", "c"],
+            "o": { "a": { "x": 1 }, "b": 2 },
+            "p": { "a": { "y": 2 } },
+            "none": null
+        });
+        let truth_table = vec![
+            ("clicks + 1", json!(6)),
+            ("clicks - views", json!(-195)),
+            ("clicks * 3", json!(15)),
+            ("views / 4", json!(50)),
+            ("clicks / views", json!(0.025)),
+            ("views % 7", json!(4)),
+            ("rate * 2", json!(3.0)),
+            ("rate % 1", json!(0.5)),
+            ("big + 1", json!(i64::MAX as f64 + 1.0)),
+            ("1 + clicks * 2 - 1", json!(10)),
+            ("(1 + clicks) * 2", json!(12)),
+            ("views / 10 / 2", json!(10)),
+            ("first + \" \" + last", json!("Jo Doe")),
+            ("tags + more", json!(["a", "b", "b", "c"])),
+            ("tags - more", json!(["a"])),
+            ("o + p", json!({ "a": { "y": 2 }, "b": 2 })),
+            ("o * p", json!({ "a": { "x": 1, "y": 2 }, "b": 2 })),
+            ("none + clicks", json!(5)),
+            (
+                "o + { c := clicks * 2 }",
+                json!({ "a": { "x": 1 }, "b": 2, "c": 10 }),
+            ),
+            ("tags | .length * 10", json!(20)),
+            ("{ ctr := clicks / views }", json!({ "ctr": 0.025 })),
+        ];
+        for (cmd, expected) in truth_table {
+            let result = eval(obj.clone(), &parse_cmd(cmd).unwrap(), EvalMode::Strict)
+                .expect("eval should not fail");
+            assert_eq!(result, Some(expected), "cmd: {}", cmd);
+        }
+
+        let run = |cmd, mode| eval(obj.clone(), &parse_cmd(cmd).unwrap(), mode);
+        assert_eq!(run("missing? + 1", EvalMode::Strict).unwrap(), None);
+        for (cmd, error) in &[
+            ("clicks / 0", "division by zero"),
+            ("rate % 0", "division by zero"),
+            ("first + clicks", "expected string but found number"),
+            ("clicks - tags", "expected number but found array"),
+            ("first * 2", "expected number but found string"),
+        ] {
+            match run(cmd, EvalMode::Strict) {
+                Err(e) => assert!(e.to_string().contains(error), "cmd: {}, got: {}", cmd, e),
+                r => panic!("expected an error for {}, got: {:?}", cmd, r),
+            }
+            assert_eq!(
+                run(cmd, EvalMode::Lenient).unwrap(),
+                Some(json!(null)),
+                "cmd: {}",
+                cmd
+            );
+        }
+        assert_eq!(
+            apply_filter(
+                obj.clone(),
+                &parse_cmd("clicks / views > 0.02").unwrap(),
+                EvalMode::Strict
+            )
+            .unwrap(),
+            Some(obj.clone())
+        );
+    }
+
+    #[test]
+    fn conditional_test() {
+        let label =
+            "if CTR > 0.05 then \"good\" elif CTR > 0.01 then \"ok\" elif CTR > 0 then Clicks else \"none\" end";
+        let truth_table = vec![
+            (json!({ "CTR": 0.07, "Clicks": 7 }), label, json!("good")),
+            (json!({ "CTR": 0.05, "Clicks": 5 }), label, json!("ok")),
+            (json!({ "CTR": 0.001, "Clicks": 1 }), label, json!(1)),
+            (json!({ "CTR": 0, "Clicks": 0 }), label, json!("none")),
+            (
+                json!({ "a": 1, "b": 2 }),
+                "if a > b then a else b end * 10",
+                json!(20),
+            ),
+            (
+                json!({ "a": 1, "b": 2 }),
+                "if a = 2 then { x := a } end",
+                json!({ "a": 1, "b": 2 }),
+            ),
+            (
+                json!({ "n": "Ann", "tags": ["x"] }),
+                "{ n := n; kind := if tags != null then tags | [0] else \"-\" end }",
+                json!({ "n": "Ann", "kind": "x" }),
+            ),
+        ];
+        for (obj, cmd, expected) in truth_table {
+            let result = eval(obj, &parse_cmd(cmd).unwrap(), EvalMode::Strict)
+                .expect("eval should not fail");
+            assert_eq!(result, Some(expected), "cmd: {}", cmd);
+        }
+
+        // conditions fail like filters do, lenient ones just don't hold
+        let cmd = parse_cmd("if name > 1 then 1 else 2 end").unwrap();
+        assert_eq!(
+            eval(json!({ "name": true }), &cmd, EvalMode::Lenient).unwrap(),
+            Some(json!(2))
+        );
+        assert!(matches!(
+            eval(json!({ "name": true }), &cmd, EvalMode::Strict),
+            Err(JqrError::TypeMismatch { .. })
+        ));
+
+        // records are labelled one by one while streaming, also ahead of an aggregate
+        let run = |query: &str| {
+            stream((0..10).map(sample_json), query, EvalMode::Strict)
+                .expect("streaming_eval should not fail")
+        };
+        assert!(can_apply_streaming(
+            &parse_cmd("if i > 1 then 1 end").unwrap()
+        ));
+        assert_eq!(
+            run("i > 6 | if i % 2 = 0 then \"even\" else \"odd\" end"),
+            vec![json!("odd"), json!("even"), json!("odd")]
+        );
+        assert_eq!(
+            run("#[..] | group_by(if age >= 10 then \"adult\" else \"minor\" end) | { k := .key; n := count }"),
+            vec![json!({ "k": "adult", "n": 3 }), json!({ "k": "minor", "n": 7 })]
+        );
+    }
+
+    #[test]
+    fn iter_slicing_test() {
+        let cmd = "#[10..30]";
+        let input_size = 30;
+
+        let buffer = stream((0..input_size).map(sample_json), cmd, EvalMode::Lenient)
             .expect("streaming_eval shouldn't throw errors");
 
         let result = buffer;
 
-        let expected: Vec<Value> = (10..30).map(|i| sample_json(i)).collect();
+        let expected: Vec<Value> = (10..30).map(sample_json).collect();
 
         assert_eq!(
             result, expected,
@@ -609,4 +2822,22 @@ mod eval_test {
             expected, result
         );
     }
+
+    #[test]
+    fn query_file_error_test() {
+        let args: Vec<String> = vec!["jqr", "-f", "/nonexistent/query.jqr"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let cmd = CmdArgs::new(&args).unwrap();
+        match eval_cmd(cmd) {
+            // the query file is named, like input files are
+            Err(e @ JqrError::Io(_)) => assert!(
+                e.to_string().contains("/nonexistent/query.jqr: "),
+                "got: {}",
+                e
+            ),
+            r => panic!("expected an I/O error, got: {:?}", r),
+        }
+    }
 }
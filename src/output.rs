@@ -0,0 +1,229 @@
+use crate::color::{ColorFormatter, Colors};
+use serde::Serialize;
+use serde_json::ser::{CompactFormatter, Formatter, PrettyFormatter};
+use serde_json::{Serializer, Value};
+use std::io::{self, Write};
+
+/// Destination for the values a query produces, `streaming_eval` hands every result to one.
+pub trait JsonWriter {
+    fn write_json(&mut self, json: &Value) -> io::Result<()>;
+
+    /// Pushes out anything buffered so far, called once all results were written.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<F: FnMut(&Value)> JsonWriter for F {
+    fn write_json(&mut self, json: &Value) -> io::Result<()> {
+        self(json);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Indent {
+    /// Whole value on a single line.
+    Compact,
+    Spaces(usize),
+    Tab,
+}
+
+/// How values are laid out when printed, set from the `-c`, `-r`, `-j`, `--indent` and `--tab`
+/// command line options.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputFormat {
+    pub indent: Indent,
+    /// Print strings without quotes or escaping.
+    pub raw: bool,
+    /// Don't print a newline after each value.
+    pub join: bool,
+    /// Syntax color the output with this palette, `None` prints plain json.
+    pub colors: Option<Colors>,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat {
+            indent: Indent::Spaces(2),
+            raw: false,
+            join: false,
+            colors: None,
+        }
+    }
+}
+
+fn serialize<F: Formatter>(json: &Value, out: &mut Vec<u8>, formatter: F) {
+    let mut ser = Serializer::with_formatter(out, formatter);
+    // serializing a Value into a Vec can't fail, it has no non-string keys nor io to fail on
+    json.serialize(&mut ser)
+        .expect("serializing json into memory failed");
+}
+
+impl OutputFormat {
+    fn serialize<F: Formatter>(&self, json: &Value, out: &mut Vec<u8>, formatter: F) {
+        match &self.colors {
+            Some(colors) => serialize(json, out, ColorFormatter::new(formatter, colors)),
+            None => serialize(json, out, formatter),
+        }
+    }
+
+    /// Renders `json` as it should appear on the output, including the trailing newline.
+    pub fn render(&self, json: &Value) -> String {
+        let mut out: Vec<u8> = Vec::new();
+        match (json, &self.indent) {
+            (Value::String(s), _) if self.raw => out.extend_from_slice(s.as_bytes()),
+            (_, Indent::Compact) | (_, Indent::Spaces(0)) => {
+                self.serialize(json, &mut out, CompactFormatter)
+            }
+            (_, Indent::Spaces(n)) => {
+                let indent = " ".repeat(*n);
+                self.serialize(
+                    json,
+                    &mut out,
+                    PrettyFormatter::with_indent(indent.as_bytes()),
+                )
+            }
+            (_, Indent::Tab) => self.serialize(json, &mut out, PrettyFormatter::with_indent(b"\t")),
+        }
+        if !self.join {
+            out.push(b'\n');
+        }
+        String::from_utf8(out).expect("serde_json always writes valid utf-8")
+    }
+}
+
+/// Writes each value to `out` laid out as `format` says, `out` is normally a `BufWriter` over
+/// stdout so values aren't flushed one by one.
+pub struct Printer<W> {
+    out: W,
+    format: OutputFormat,
+}
+
+impl<W: Write> Printer<W> {
+    pub fn new(out: W, format: OutputFormat) -> Printer<W> {
+        Printer { out, format }
+    }
+}
+
+impl<W: Write> JsonWriter for Printer<W> {
+    fn write_json(&mut self, json: &Value) -> io::Result<()> {
+        self.out.write_all(self.format.render(json).as_bytes())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod output_test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn render_test() {
+        let json = json!({ "name": "Ford", "models": ["Fiesta", "Focus"] });
+        let format = |indent, raw, join| OutputFormat {
+            indent,
+            raw,
+            join,
+            colors: None,
+        };
+
+        assert_eq!(
+            OutputFormat::default().render(&json),
+            "{\n  \"models\": [\n    \"Fiesta\",\n    \"Focus\"\n  ],\n  \"name\": \"Ford\"\n}\n"
+        );
+        assert_eq!(
+            format(Indent::Compact, false, false).render(&json),
+            "{\"models\":[\"Fiesta\",\"Focus\"],\"name\":\"Ford\"}\n"
+        );
+        assert_eq!(
+            format(Indent::Spaces(0), false, false).render(&json!([1, 2])),
+            "[1,2]\n"
+        );
+        assert_eq!(
+            format(Indent::Tab, false, false).render(&json!([1])),
+            "[\n\t1\n]\n"
+        );
+        assert_eq!(
+            format(Indent::Spaces(4), false, false).render(&json!({ "a": 1 })),
+            "{\n    \"a\": 1\n}\n"
+        );
+
+        let raw = format(Indent::Spaces(2), true, false);
+        assert_eq!(
+            raw.render(&json!("a \"quoted\"\tword")),
+            "a \"quoted\"\tword\n"
+        );
+        assert_eq!(raw.render(&json!(["a"])), "[\n  \"a\"\n]\n");
+
+        let joined = format(Indent::Compact, true, true);
+        assert_eq!(joined.render(&json!("a")) + &joined.render(&json!(1)), "a1");
+    }
+
+    #[test]
+    fn render_colors_test() {
+        let json = json!({ "a": [1, "x", null, true] });
+        let colored = OutputFormat {
+            indent: Indent::Compact,
+            colors: Some(Colors::default()),
+            ..OutputFormat::default()
+        };
+        assert_eq!(
+            colored.render(&json),
+            "\x1b[1;39m{\x1b[0m\x1b[1;34m\"a\"\x1b[0m:\x1b[1;39m[\x1b[0m\x1b[0;36m1\x1b[0m,\
+             \x1b[0;32m\"x\"\x1b[0m,\x1b[1;30mnull\x1b[0m,\x1b[0;33mtrue\x1b[0m\
+             \x1b[1;39m]\x1b[0m\x1b[1;39m}\x1b[0m\n"
+        );
+
+        // stripping the color codes leaves exactly the plain output
+        let pretty = OutputFormat {
+            colors: Some(Colors::default()),
+            ..OutputFormat::default()
+        };
+        let mut stripped = String::new();
+        let mut in_escape = false;
+        for c in pretty.render(&json).chars() {
+            match c {
+                '\x1b' => in_escape = true,
+                'm' if in_escape => in_escape = false,
+                _ if in_escape => (),
+                c => stripped.push(c),
+            }
+        }
+        assert_eq!(stripped, OutputFormat::default().render(&json));
+    }
+
+    struct FailingWriter(io::ErrorKind);
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::from(self.0))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::from(self.0))
+        }
+    }
+
+    #[test]
+    fn printer_test() {
+        let mut printer = Printer::new(Vec::new(), OutputFormat::default());
+        printer.write_json(&json!([1])).unwrap();
+        printer.write_json(&json!("a")).unwrap();
+        printer.flush().unwrap();
+        assert_eq!(printer.out, b"[\n  1\n]\n\"a\"\n");
+
+        let mut printer = Printer::new(
+            io::BufWriter::new(FailingWriter(io::ErrorKind::BrokenPipe)),
+            OutputFormat::default(),
+        );
+        printer
+            .write_json(&json!(1))
+            .expect("buffered, not written yet");
+        let e = printer.flush().expect_err("flush should fail");
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
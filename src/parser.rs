@@ -1,35 +1,219 @@
-use pest::iterators::Pair;
+use crate::error::JqrError;
+use crate::Builtin;
+use pest::error::{ErrorVariant, InputLocation};
+use pest::iterators::{Pair, Pairs};
 use pest::Parser;
-use std::error::Error;
+use regex::Regex;
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::str::FromStr;
 
 #[derive(Parser)]
 #[grammar = "jqr.pest"]
 pub struct JQRParser;
 
+/// Comparison operator of a filter, e.g. the `>=` in `Clicks >= 10`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CompareOp {
+    /// Whether a value ordered `ordering` relative to the filter value passes this operator.
+    pub fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            CompareOp::Eq => ordering == Ordering::Equal,
+            CompareOp::Ne => ordering != Ordering::Equal,
+            CompareOp::Lt => ordering == Ordering::Less,
+            CompareOp::Le => ordering != Ordering::Greater,
+            CompareOp::Gt => ordering == Ordering::Greater,
+            CompareOp::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+impl FromStr for CompareOp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "=" => Ok(CompareOp::Eq),
+            "!=" => Ok(CompareOp::Ne),
+            "<" => Ok(CompareOp::Lt),
+            "<=" => Ok(CompareOp::Le),
+            ">" => Ok(CompareOp::Gt),
+            ">=" => Ok(CompareOp::Ge),
+            _ => Err(format!("unknown comparison operator `{}`", s)),
+        }
+    }
+}
+
+/// Operator of an arithmetic expression, e.g. the `/` in `Clicks / Impressions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl ArithOp {
+    /// `+` and `-` bind looser than `*`, `/` and `%`.
+    fn is_additive(self) -> bool {
+        matches!(self, ArithOp::Add | ArithOp::Sub)
+    }
+}
+
+impl FromStr for ArithOp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "+" => Ok(ArithOp::Add),
+            "-" => Ok(ArithOp::Sub),
+            "*" => Ok(ArithOp::Mul),
+            "/" => Ok(ArithOp::Div),
+            "%" => Ok(ArithOp::Rem),
+            _ => Err(format!("unknown arithmetic operator `{}`", s)),
+        }
+    }
+}
+
+/// A regex written in the query, compiled once when the query is parsed rather than for every
+/// record it's matched against.
+#[derive(Debug, Clone)]
+pub struct Pattern(pub Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for Pattern {}
+
+/// Condition a filter keeps values by, comparisons combined with `and`, `or` and `not`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Predicate {
+    /// `lhs op rhs`, either side a path, a computation or a literal.
+    Compare(Box<QueryCmd>, CompareOp, Box<QueryCmd>),
+    /// `path ~ "regex"`, a string the regex matches somewhere in.
+    Matches(Box<QueryCmd>, Pattern),
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+    Not(Box<Predicate>),
+}
+
+/// Every `step`th position from `start` up to, but excluding, `end`. Negative bounds count back
+/// from the end, missing ones mean the start or end of the whole sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slice {
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+    pub step: usize,
+}
+
+impl Slice {
+    /// Start and end positions within a sequence of `len` elements, clamped to its bounds.
+    pub fn bounds(&self, len: usize) -> (usize, usize) {
+        let resolve = |bound: i64| {
+            if bound < 0 {
+                len.saturating_sub(bound.unsigned_abs() as usize)
+            } else {
+                len.min(bound as usize)
+            }
+        };
+        let start = self.start.map(resolve).unwrap_or(0);
+        let end = self.end.map(resolve).unwrap_or(len);
+        (start, end.max(start))
+    }
+}
+
+/// One step of a path that may match any number of values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    Key(String),
+    /// `*`, every element of an array or value of an object.
+    AnyChild,
+    /// `**`, the value itself and everything nested in it, at any depth.
+    AnyDepth,
+}
+
+/// `group_by(path) | { ... }`, one object per distinct value of the path, with the aggregates of
+/// the records that yield it. Only the aggregates are kept, not the records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grouping {
+    pub key: Box<QueryCmd>,
+    pub fields: Vec<(String, GroupField)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupField {
+    /// `.key`, the value the group's records were grouped by.
+    Key,
+    /// An aggregate like `sum(Clicks)`, its own arguments followed by the path to the value each
+    /// record adds to it. Without a path it takes whole records.
+    Aggregate(&'static Builtin, Vec<QueryCmd>, Option<Box<QueryCmd>>),
+}
+
 #[derive(Debug, Eq, Clone)]
 pub enum QueryCmd {
-    ArrayIndexAccess(Vec<usize>),
+    ArrayIndexAccess(Vec<i64>),
+    Slice(Slice),
+    /// `#[...]`, picks records from the input stream by position instead of indexing into the
+    /// current value, holds the `ArrayIndexAccess` or `Slice` saying which ones.
+    SelectRecords(Box<QueryCmd>),
     KeywordAccess(Vec<String>),
+    /// Path with `*`, `**` or `..key` in it, yields an array of every value it matches.
+    PathSearch(Vec<PathSegment>),
+    /// `path?`, no result at all instead of `null` or an error when the path isn't there.
+    Optional(Box<QueryCmd>),
+    /// `a // b // ...`, the first alternative that is there and isn't `null`.
+    Alternative(Vec<QueryCmd>),
+    Literal(Value),
     MultiCmd(Vec<QueryCmd>),
     TransformIntoObject(Vec<(String, QueryCmd)>),
-    FilterCmd(Box<QueryCmd>, String, String),
-    ListKeys,
-    ListValues,
-    Count,
+    FilterCmd(Predicate),
+    /// `name(args)`, or `.name` without arguments, a builtin applied to the current value. Its
+    /// arguments are queries evaluated against that same value, or against each of its
+    /// elements for the path of `sort_by` and the like.
+    FunctionCall(&'static Builtin, Vec<QueryCmd>),
+    /// Regex argument of `test`, `capture` and the other regex functions.
+    Pattern(Pattern),
+    GroupBy(Grouping),
+    /// `a + b` and the other arithmetic operators, both sides evaluated against the current value.
+    Arithmetic(Box<QueryCmd>, ArithOp, Box<QueryCmd>),
+    /// `if c then x elif d then y else z end`, the query of the first branch whose condition
+    /// holds, else the one of `else`. Without an `else` the value is passed on as it is.
+    Conditional(Vec<(Predicate, QueryCmd)>, Option<Box<QueryCmd>>),
 }
 
 impl PartialEq for QueryCmd {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (QueryCmd::ArrayIndexAccess(xs), QueryCmd::ArrayIndexAccess(ys)) => xs == ys,
+            (QueryCmd::Slice(x), QueryCmd::Slice(y)) => x == y,
+            (QueryCmd::SelectRecords(x), QueryCmd::SelectRecords(y)) => x == y,
             (QueryCmd::KeywordAccess(xs), QueryCmd::KeywordAccess(ys)) => xs == ys,
+            (QueryCmd::PathSearch(xs), QueryCmd::PathSearch(ys)) => xs == ys,
+            (QueryCmd::Optional(x), QueryCmd::Optional(y)) => x == y,
+            (QueryCmd::Alternative(xs), QueryCmd::Alternative(ys)) => xs == ys,
+            (QueryCmd::Literal(x), QueryCmd::Literal(y)) => x == y,
             (QueryCmd::MultiCmd(xs), QueryCmd::MultiCmd(ys)) => xs == ys,
-            (QueryCmd::ListKeys, QueryCmd::ListKeys) => true,
-            (QueryCmd::ListValues, QueryCmd::ListValues) => true,
-            (QueryCmd::Count, QueryCmd::Count) => true,
-            (QueryCmd::FilterCmd(c1, op1, v1), QueryCmd::FilterCmd(c2, op2, v2)) => {
-                c1 == c2 && op1 == op2 && v1 == v2
+            (QueryCmd::FunctionCall(f, xs), QueryCmd::FunctionCall(g, ys)) => f == g && xs == ys,
+            (QueryCmd::Pattern(x), QueryCmd::Pattern(y)) => x == y,
+            (QueryCmd::GroupBy(x), QueryCmd::GroupBy(y)) => x == y,
+            (QueryCmd::Arithmetic(x1, op1, x2), QueryCmd::Arithmetic(y1, op2, y2)) => {
+                x1 == y1 && op1 == op2 && x2 == y2
             }
+            (QueryCmd::Conditional(xs, x), QueryCmd::Conditional(ys, y)) => xs == ys && x == y,
+            (QueryCmd::FilterCmd(p1), QueryCmd::FilterCmd(p2)) => p1 == p2,
             (QueryCmd::TransformIntoObject(x_ps), QueryCmd::TransformIntoObject(y_ps)) => {
                 x_ps == y_ps
             }
@@ -39,107 +223,695 @@ impl PartialEq for QueryCmd {
 }
 
 impl QueryCmd {
+    #[cfg(test)]
     fn keyword_access(kws: &[&str]) -> QueryCmd {
         QueryCmd::KeywordAccess(kws.iter().map(|k| k.to_string()).collect())
     }
 
-    fn filter_cmd(f: QueryCmd, op: &str, v: &str) -> QueryCmd {
-        QueryCmd::FilterCmd(Box::new(f), op.to_string(), v.to_string())
+    #[cfg(test)]
+    fn slice(start: Option<i64>, end: Option<i64>, step: usize) -> QueryCmd {
+        QueryCmd::Slice(Slice { start, end, step })
     }
-}
 
-// fn next_res<T>(op: Pair<T>) -> Result<T, Box<dyn Error>> {
-//     let err: Box<dyn Error> = String::from("Empty top level parse result").into();
-//     op.next().ok_or(err)
-// }
+    #[cfg(test)]
+    fn call(name: &str, args: Vec<QueryCmd>) -> QueryCmd {
+        QueryCmd::FunctionCall(crate::builtin(name).expect("unknown builtin"), args)
+    }
 
-fn parse_keyword(expr: Pair<Rule>) -> Result<QueryCmd, Box<dyn Error>> {
-    let kws: Vec<_> = expr.into_inner().map(|kw| kw.as_str()).collect();
-    //println!("kws: {:?}", kws);
-    Ok(QueryCmd::keyword_access(&kws))
+    #[cfg(test)]
+    fn arithmetic(lhs: QueryCmd, op: ArithOp, rhs: QueryCmd) -> QueryCmd {
+        QueryCmd::Arithmetic(Box::new(lhs), op, Box::new(rhs))
+    }
+
+    #[cfg(test)]
+    fn filter_cmd(f: QueryCmd, op: CompareOp, v: Value) -> QueryCmd {
+        QueryCmd::FilterCmd(Predicate::compare(f, op, v))
+    }
 }
 
-fn parse_err(msg: &str) -> Box<dyn Error> {
-    String::from(msg).into()
+#[cfg(test)]
+impl Predicate {
+    fn compare(f: QueryCmd, op: CompareOp, v: Value) -> Predicate {
+        Predicate::Compare(Box::new(f), op, Box::new(QueryCmd::Literal(v)))
+    }
 }
 
-fn parse_expr(expr: Pair<Rule>) -> Result<QueryCmd, Box<dyn Error>> {
+/// Name of a key, either bare like `node_id` or quoted like `"Content-Type"` with json escapes.
+fn parse_key(expr: Pair<Rule>) -> Result<String, JqrError> {
     match expr.as_rule() {
-        Rule::valsExpr => Ok(QueryCmd::ListValues),
-        Rule::keysExpr => Ok(QueryCmd::ListKeys),
-        Rule::countExpr => Ok(QueryCmd::Count),
-        Rule::multiKeyword => parse_keyword(expr),
-        Rule::indexAccess => {
-            let idx: Vec<usize> = expr
-                .into_inner()
-                .map(|kw| kw.as_str().parse::<usize>().unwrap())
-                .collect();
-            Ok(QueryCmd::ArrayIndexAccess(idx))
+        Rule::quotedKeyword => serde_json::from_str(expr.as_str())
+            .map_err(|e| JqrError::parse(&format!("invalid quoted key, {}", e), span_of(&expr))),
+        _ => Ok(expr.as_str().to_string()),
+    }
+}
+
+fn parse_keyword(expr: Pair<Rule>) -> Result<QueryCmd, JqrError> {
+    let mut path: Vec<PathSegment> = vec![];
+    for segment in expr.into_inner() {
+        match segment.as_rule() {
+            Rule::anyChild => path.push(PathSegment::AnyChild),
+            Rule::anyDepth => path.push(PathSegment::AnyDepth),
+            Rule::descendKeyword => {
+                let parent = segment.clone();
+                let key = next_arg(&mut segment.into_inner(), &parent, "missing key after `..`")?;
+                path.push(PathSegment::AnyDepth);
+                path.push(PathSegment::Key(parse_key(key)?));
+            }
+            _ => path.push(PathSegment::Key(parse_key(segment)?)),
         }
-        Rule::rangeIndexAccess => {
-            let idx_range: Vec<usize> = expr
-                .into_inner()
-                .map(|e| e.as_str().parse::<usize>().unwrap())
-                .collect();
-            if idx_range.len() != 2 {
-                panic!("expected [from..to], got: {:?}", idx_range)
+    }
+
+    let keys: Vec<String> = path
+        .iter()
+        .filter_map(|segment| match segment {
+            PathSegment::Key(k) => Some(k.to_string()),
+            _ => None,
+        })
+        .collect();
+    if keys.len() == path.len() {
+        Ok(QueryCmd::KeywordAccess(keys))
+    } else {
+        Ok(QueryCmd::PathSearch(path))
+    }
+}
+
+fn span_of(expr: &Pair<Rule>) -> (usize, usize) {
+    let span = expr.as_span();
+    (span.start(), span.end())
+}
+
+fn parse_index(expr: Pair<Rule>) -> Result<i64, JqrError> {
+    expr.as_str()
+        .parse::<i64>()
+        .map_err(|e| JqrError::parse(&format!("invalid array index, {}", e), span_of(&expr)))
+}
+
+fn parse_slice(expr: Pair<Rule>) -> Result<Slice, JqrError> {
+    let mut slice = Slice {
+        start: None,
+        end: None,
+        step: 1,
+    };
+    for bound in expr.into_inner() {
+        let span = span_of(&bound);
+        match bound.as_rule() {
+            Rule::sliceStep => {
+                slice.step = match bound.as_str().parse::<usize>() {
+                    Ok(step) if step > 0 => step,
+                    _ => return Err(JqrError::parse("slice step must be at least 1", span)),
+                }
+            }
+            rule => {
+                let index = bound
+                    .into_inner()
+                    .next()
+                    .ok_or_else(|| JqrError::parse("slice - missing bound", span))?;
+                if rule == Rule::sliceStart {
+                    slice.start = Some(parse_index(index)?);
+                } else {
+                    slice.end = Some(parse_index(index)?);
+                }
             }
+        }
+    }
+    Ok(slice)
+}
+
+fn next_arg<'a>(
+    args: &mut Pairs<'a, Rule>,
+    parent: &Pair<'a, Rule>,
+    msg: &str,
+) -> Result<Pair<'a, Rule>, JqrError> {
+    args.next()
+        .ok_or_else(|| JqrError::parse(msg, span_of(parent)))
+}
+
+/// Filter values are written the way json writes them, so serde_json decodes them, escapes and all.
+fn parse_literal(expr: Pair<Rule>) -> Result<Value, JqrError> {
+    serde_json::from_str(expr.as_str())
+        .map_err(|e| JqrError::parse(&format!("invalid filter value, {}", e), span_of(&expr)))
+}
 
-            Ok(QueryCmd::ArrayIndexAccess(
-                (idx_range[0]..idx_range[1]).collect(),
+/// Folds the operands of an `and` or `or` chain left to right, skipping the operator tokens.
+fn parse_chain(
+    expr: Pair<Rule>,
+    combine: fn(Box<Predicate>, Box<Predicate>) -> Predicate,
+) -> Result<Predicate, JqrError> {
+    let parent = expr.clone();
+    let mut operands = expr
+        .into_inner()
+        .filter(|p| !matches!(p.as_rule(), Rule::andOp | Rule::orOp));
+    let first = operands
+        .next()
+        .ok_or_else(|| JqrError::parse("predicate - missing operand", span_of(&parent)))?;
+    operands.try_fold(parse_predicate(first)?, |acc, p| {
+        Ok(combine(Box::new(acc), Box::new(parse_predicate(p)?)))
+    })
+}
+
+fn parse_predicate(expr: Pair<Rule>) -> Result<Predicate, JqrError> {
+    let parent = expr.clone();
+    match expr.as_rule() {
+        Rule::filterExpr => parse_chain(expr, Predicate::Or),
+        Rule::andPredicate => parse_chain(expr, Predicate::And),
+        Rule::notPredicate => {
+            let mut args = expr.into_inner().skip(1);
+            let operand = args.next().ok_or_else(|| {
+                JqrError::parse("notPredicate - missing operand", span_of(&parent))
+            })?;
+            Ok(Predicate::Not(Box::new(parse_predicate(operand)?)))
+        }
+        Rule::regexMatch => {
+            let mut expr = expr.into_inner();
+            let query_expr = next_arg(&mut expr, &parent, "regexMatch - invalid queryExpr")?;
+            next_arg(&mut expr, &parent, "regexMatch - missing operator")?;
+            let pattern = next_arg(&mut expr, &parent, "regexMatch - missing regex")?;
+            Ok(Predicate::Matches(
+                Box::new(parse_expr(query_expr)?),
+                parse_pattern(pattern)?,
             ))
         }
-        Rule::filterExpr => {
+        Rule::comparison => {
             let mut expr = expr.into_inner();
-            let query_expr = expr
-                .next()
-                .ok_or(parse_err("filterExpr - invalid queryExpr"))?;
-            let op_expr = expr
-                .next()
-                .ok_or(parse_err("filterExpr - invalid operatorExpr"))?;
-            let val_expr = expr
-                .next()
-                .ok_or(parse_err("filterExpr - invalid valueExpr"))?;
-
-            Ok(QueryCmd::filter_cmd(
-                parse_keyword(query_expr)?,
-                op_expr.as_str(),
-                val_expr.as_str(),
+            let lhs = next_arg(&mut expr, &parent, "comparison - invalid left operand")?;
+            let op_expr = next_arg(&mut expr, &parent, "comparison - invalid operatorExpr")?;
+            let comparand = next_arg(&mut expr, &parent, "comparison - invalid right operand")?;
+            let rhs = next_arg(
+                &mut comparand.into_inner(),
+                &parent,
+                "comparison - invalid right operand",
+            )?;
+            let op = op_expr
+                .as_str()
+                .parse()
+                .map_err(|e: String| JqrError::parse(&e, span_of(&op_expr)))?;
+
+            Ok(Predicate::Compare(
+                Box::new(parse_expr(lhs)?),
+                op,
+                Box::new(parse_expr(rhs)?),
             ))
         }
-        Rule::multiExpr => {
-            let cmds = expr
+        r => Err(JqrError::parse(
+            &format!("unexpected rule {:?} in filter", r),
+            span_of(&parent),
+        )),
+    }
+}
+
+/// Regexes are string literals, so they are compiled before any input is read.
+fn parse_pattern(expr: Pair<Rule>) -> Result<Pattern, JqrError> {
+    let span = span_of(&expr);
+    let source = match expr.as_rule() {
+        Rule::literal => expr.into_inner().next().map(parse_literal).transpose()?,
+        Rule::stringValue => Some(parse_literal(expr)?),
+        _ => None,
+    };
+    let source = match source {
+        Some(Value::String(source)) => source,
+        _ => {
+            return Err(JqrError::parse(
+                "expected a regex string like \"[0-9]+\"",
+                span,
+            ))
+        }
+    };
+    Regex::new(&source).map(Pattern).map_err(|e| {
+        // syntax errors come with the pattern drawn out, the last line says what's wrong with it
+        let e = e.to_string();
+        let reason = e
+            .lines()
+            .last()
+            .unwrap_or_default()
+            .trim_start_matches("error: ");
+        JqrError::parse(&format!("invalid regex, {}", reason), span)
+    })
+}
+
+/// Looks the function up in the builtin registry, so unknown names and wrong argument counts
+/// are reported before any input is read.
+fn parse_call(expr: Pair<Rule>) -> Result<QueryCmd, JqrError> {
+    let parent = expr.clone();
+    let mut args = expr.into_inner();
+    let name = next_arg(&mut args, &parent, "functionCall - missing name")?;
+    let f = crate::builtin(name.as_str()).ok_or_else(|| JqrError::Parse {
+        message: format!("unknown function `{}`", name.as_str()),
+        span: span_of(&name),
+        query: String::new(),
+        hint: Some(String::from("see `jqr --help` for the list of functions")),
+    })?;
+    let mut parsed = vec![];
+    if f.takes_regex() {
+        parsed.extend(
+            args.next()
+                .map(parse_pattern)
+                .transpose()?
+                .map(QueryCmd::Pattern),
+        );
+    }
+    for arg in args {
+        parsed.push(parse_expr(arg)?);
+    }
+    let args = parsed;
+    if args.len() < f.arity || args.len() > f.arity + f.optional {
+        let expected = match f.optional {
+            0 => format!("{} argument(s)", f.arity),
+            n => format!("{} to {} arguments", f.arity, f.arity + n),
+        };
+        return Err(JqrError::parse(
+            &format!("`{}` takes {}, got {}", f.name, expected, args.len()),
+            span_of(&parent),
+        ));
+    }
+    f.check_args(&args)
+        .map_err(|message| JqrError::parse(&message, span_of(&parent)))?;
+    Ok(QueryCmd::FunctionCall(f, args))
+}
+
+fn is_call_of(expr: &Pair<Rule>, name: &str) -> bool {
+    expr.as_rule() == Rule::functionCall
+        && expr.clone().into_inner().next().map(|n| n.as_str()) == Some(name)
+}
+
+/// Commands of a pipe, where `group_by(path)` directly followed by `{ ... }` is one `GroupBy`.
+fn parse_pipe(exprs: Pairs<Rule>) -> Result<Vec<QueryCmd>, JqrError> {
+    let mut exprs = exprs.peekable();
+    let mut cmds = vec![];
+    while let Some(expr) = exprs.next() {
+        let grouped = is_call_of(&expr, "group_by")
+            && exprs.peek().map(|e| e.as_rule()) == Some(Rule::newObjExpr);
+        match (parse_expr(expr)?, exprs.next_if(|_| grouped)) {
+            (QueryCmd::FunctionCall(_, mut args), Some(obj)) => {
+                let fields = obj
+                    .into_inner()
+                    .map(|e| {
+                        let parent = e.clone();
+                        let mut args = e.into_inner();
+                        let name = next_arg(&mut args, &parent, "assignProp - missing name")?;
+                        let value = next_arg(&mut args, &parent, "assignProp - missing value")?;
+                        Ok((parse_key(name)?, parse_group_field(value)?))
+                    })
+                    .collect::<Result<Vec<(String, GroupField)>, JqrError>>()?;
+                cmds.push(QueryCmd::GroupBy(Grouping {
+                    key: Box::new(args.remove(0)),
+                    fields,
+                }))
+            }
+            (cmd, _) => cmds.push(cmd),
+        }
+    }
+    Ok(cmds)
+}
+
+/// A property of a `group_by(path) | { ... }` projection, `.key` or an aggregate.
+fn parse_group_field(expr: Pair<Rule>) -> Result<GroupField, JqrError> {
+    let span = span_of(&expr);
+    let not_aggregate = || JqrError::Parse {
+        message: String::from("properties of a `group_by` object are `.key` or aggregates"),
+        span,
+        query: String::new(),
+        hint: Some(String::from(
+            "aggregates are count, count_distinct, sum, avg, min, max, percentile and top, \
+             e.g. `{ k := .key; clicks := sum(Clicks); n := count }`",
+        )),
+    };
+    match expr.as_rule() {
+        Rule::functionCall
+            if is_call_of(&expr, "key") && expr.clone().into_inner().count() == 1 =>
+        {
+            Ok(GroupField::Key)
+        }
+        Rule::functionCall => {
+            let parent = expr.clone();
+            let mut args = expr.into_inner();
+            let name = next_arg(&mut args, &parent, "functionCall - missing name")?;
+            let f = crate::builtin(name.as_str())
+                .filter(|f| f.folds())
+                .ok_or_else(not_aggregate)?;
+            let mut args = args
+                .map(parse_expr)
+                .collect::<Result<Vec<QueryCmd>, JqrError>>()?;
+            let given = args.len();
+            let path = if given > f.arity {
+                args.pop().map(Box::new)
+            } else {
+                None
+            };
+            if args.len() != f.arity {
+                return Err(JqrError::parse(
+                    &format!(
+                        "`{}` takes {} argument(s) and the path to aggregate, got {}",
+                        f.name, f.arity, given
+                    ),
+                    span,
+                ));
+            }
+            f.check_args(&args)
+                .map_err(|message| JqrError::parse(&message, span))?;
+            Ok(GroupField::Aggregate(f, args, path))
+        }
+        // a bare `count` is the aggregate here, the group has no keys of its own
+        Rule::multiKeyword => match crate::builtin(expr.as_str().trim()) {
+            Some(f) if f.folds() && f.arity == 0 => Ok(GroupField::Aggregate(f, vec![], None)),
+            _ => Err(not_aggregate()),
+        },
+        _ => Err(not_aggregate()),
+    }
+}
+
+/// Operands joined by arithmetic operators, `*`, `/` and `%` binding tighter than `+` and `-`,
+/// and operators of the same kind applied left to right.
+fn parse_arithmetic(expr: Pair<Rule>) -> Result<QueryCmd, JqrError> {
+    let parent = expr.clone();
+    let mut exprs = expr.into_inner();
+    let first = next_arg(&mut exprs, &parent, "arithExpr - missing operand")?;
+    // the terms added up so far, each with the operator that follows it
+    let mut terms: Vec<(QueryCmd, ArithOp)> = vec![];
+    let mut end = first.as_span().end();
+    let mut product = parse_expr(first)?;
+    while let Some(op_expr) = exprs.next() {
+        let op: ArithOp = op_expr
+            .as_str()
+            .parse()
+            .map_err(|e: String| JqrError::parse(&e, span_of(&op_expr)))?;
+        let operand = next_arg(&mut exprs, &parent, "arithExpr - missing operand")?;
+        // `Content-Type` is far more likely a key missing its quotes than a subtraction
+        let (start, op_end) = span_of(&op_expr);
+        if op == ArithOp::Sub
+            && end == start
+            && operand.as_span().start() == op_end
+            && operand
+                .as_str()
+                .starts_with(|c: char| c.is_alphabetic() || c == '_')
+        {
+            return Err(JqrError::Parse {
+                message: String::from("unexpected `-` inside a key"),
+                span: (start, start),
+                query: String::new(),
+                hint: Some(String::from(QUOTED_KEY_HINT)),
+            });
+        }
+        end = operand.as_span().end();
+        let operand = parse_expr(operand)?;
+        if op.is_additive() {
+            terms.push((product, op));
+            product = operand;
+        } else {
+            product = QueryCmd::Arithmetic(Box::new(product), op, Box::new(operand));
+        }
+    }
+    let mut terms = terms.into_iter();
+    Ok(match terms.next() {
+        None => product,
+        Some((first, op)) => {
+            let (sum, op) = terms.fold((first, op), |(sum, op), (term, next)| {
+                (
+                    QueryCmd::Arithmetic(Box::new(sum), op, Box::new(term)),
+                    next,
+                )
+            });
+            QueryCmd::Arithmetic(Box::new(sum), op, Box::new(product))
+        }
+    })
+}
+
+fn parse_conditional(expr: Pair<Rule>) -> Result<QueryCmd, JqrError> {
+    let parent = expr.clone();
+    let mut exprs = expr.into_inner();
+    let mut branches = vec![];
+    let mut otherwise = None;
+    while let Some(keyword) = exprs.next() {
+        match keyword.as_rule() {
+            Rule::ifOp | Rule::elifOp => {
+                let condition = next_arg(&mut exprs, &parent, "conditional - missing condition")?;
+                next_arg(&mut exprs, &parent, "conditional - missing `then`")?;
+                let branch = next_arg(&mut exprs, &parent, "conditional - missing branch")?;
+                branches.push((parse_predicate(condition)?, parse_expr(branch)?));
+            }
+            Rule::elseOp => {
+                let branch = next_arg(&mut exprs, &parent, "conditional - missing else branch")?;
+                otherwise = Some(Box::new(parse_expr(branch)?));
+            }
+            _ => (),
+        }
+    }
+    Ok(QueryCmd::Conditional(branches, otherwise))
+}
+
+fn parse_expr(expr: Pair<Rule>) -> Result<QueryCmd, JqrError> {
+    match expr.as_rule() {
+        Rule::functionCall => parse_call(expr),
+        Rule::arithExpr => parse_arithmetic(expr),
+        Rule::conditional => parse_conditional(expr),
+        Rule::multiKeyword => parse_keyword(expr),
+        Rule::optionalPath => {
+            let parent = expr.clone();
+            let path = next_arg(&mut expr.into_inner(), &parent, "optional - missing path")?;
+            Ok(QueryCmd::Optional(Box::new(parse_expr(path)?)))
+        }
+        Rule::alternativeExpr => {
+            let alternatives = expr
                 .into_inner()
-                .map(|expr| parse_expr(expr).expect("parseMulti failed"));
-            Ok(QueryCmd::MultiCmd(cmds.collect()))
+                .map(parse_expr)
+                .collect::<Result<Vec<QueryCmd>, JqrError>>()?;
+            Ok(QueryCmd::Alternative(alternatives))
+        }
+        Rule::literal => {
+            let parent = expr.clone();
+            let value = next_arg(&mut expr.into_inner(), &parent, "literal - missing value")?;
+            Ok(QueryCmd::Literal(parse_literal(value)?))
+        }
+        Rule::indexAccess => {
+            let idx = expr
+                .into_inner()
+                .map(parse_index)
+                .collect::<Result<Vec<i64>, JqrError>>()?;
+            Ok(QueryCmd::ArrayIndexAccess(idx))
+        }
+        Rule::rangeIndexAccess => Ok(QueryCmd::Slice(parse_slice(expr)?)),
+        Rule::filterExpr => Ok(QueryCmd::FilterCmd(parse_predicate(expr)?)),
+        Rule::streamSelect => {
+            let parent = expr.clone();
+            let mut args = expr.into_inner();
+            let index = next_arg(&mut args, &parent, "streamSelect - missing index access")?;
+            Ok(QueryCmd::SelectRecords(Box::new(parse_expr(index)?)))
+        }
+        Rule::streamExpr | Rule::multiExpr => {
+            let mut cmds = parse_pipe(expr.into_inner())?;
+            if cmds.len() == 1 {
+                Ok(cmds.remove(0))
+            } else {
+                Ok(QueryCmd::MultiCmd(cmds))
+            }
         }
         Rule::newObjExpr => {
-            let properties = expr.into_inner().map(|e| {
-                let mut args = e.into_inner();
-                let prop_name = args.next().unwrap().as_str().to_string(); //todo handler errors better
-                let prop_value = parse_expr(args.next().unwrap()).unwrap();
-                (prop_name, prop_value)
-            });
-            Ok(QueryCmd::TransformIntoObject(properties.collect()))
+            let properties = expr
+                .into_inner()
+                .map(|e| {
+                    let parent = e.clone();
+                    let mut args = e.into_inner();
+                    let prop_name = next_arg(&mut args, &parent, "assignProp - missing name")?;
+                    let prop_value = next_arg(&mut args, &parent, "assignProp - missing value")?;
+                    Ok((parse_key(prop_name)?, parse_expr(prop_value)?))
+                })
+                .collect::<Result<Vec<(String, QueryCmd)>, JqrError>>()?;
+            Ok(QueryCmd::TransformIntoObject(properties))
+        }
+        r => Err(JqrError::parse(
+            &format!("unexpected rule {:?}", r),
+            span_of(&expr),
+        )),
+    }
+}
+
+/// Describes a grammar rule in jqr terms, used when listing what the parser expected.
+fn describe_rule(rule: &Rule) -> String {
+    match rule {
+        Rule::arrayIndex | Rule::sliceStart | Rule::sliceEnd => "an array index like `0` or `-1`",
+        Rule::sliceStep => "a slice step like `2`",
+        Rule::indexAccess | Rule::rangeIndexAccess => "an index access like `[0]` or `[0..5]`",
+        Rule::streamSelect | Rule::streamExpr => "a record selection like `#[0]` or `#[0..5]`",
+        Rule::optionalPath => "an optional path like `name?` or `[5]?`",
+        Rule::alternativeExpr => "`//` followed by a default",
+        Rule::literal => "a value: string, number, boolean or null",
+        Rule::anyChild | Rule::anyDepth | Rule::descendKeyword => {
+            "a path wildcard like `*`, `**` or `..name`"
+        }
+        Rule::singleKeyword | Rule::quotedKeyword | Rule::multiKeyword => {
+            "a keyword like `name`, `author.login` or `[\"Content-Type\"]`"
+        }
+        Rule::stringContent | Rule::escape => "string contents",
+        Rule::assignProp => "a property assignment like `name := expr`",
+        Rule::newObjExpr => "an object expression `{ name := expr }`",
+        Rule::stringValue
+        | Rule::integerValue
+        | Rule::floatValue
+        | Rule::exponent
+        | Rule::boolValue
+        | Rule::nullValue => "a filter value: string, number, boolean or null",
+        Rule::comparand => {
+            "a value to compare with: string, number, boolean, null, a path or arithmetic"
+        }
+        Rule::filterOp => "a comparison operator: `=`, `!=`, `<`, `<=`, `>` or `>=`",
+        Rule::matchOp => "`~`",
+        Rule::arithOp => "an arithmetic operator: `+`, `-`, `*`, `/` or `%`",
+        Rule::arithExpr => "arithmetic like `Clicks / Impressions`",
+        Rule::regexMatch => "a regex match like `name ~ \"^a\"`",
+        Rule::filterExpr | Rule::andPredicate | Rule::comparison => {
+            "a filter like `name = \"value\"`"
+        }
+        Rule::notPredicate | Rule::notOp => "`not`",
+        Rule::conditional | Rule::ifOp => "a conditional `if ... then ... else ... end`",
+        Rule::thenOp => "`then`",
+        Rule::elifOp | Rule::elseOp | Rule::endOp => "`elif`, `else` or `end`",
+        Rule::andOp => "`and`",
+        Rule::orOp => "`or`",
+        Rule::functionName | Rule::functionCall => {
+            "a function call like `.count` or `has(\"name\")`"
+        }
+        Rule::EOI => "end of query",
+        Rule::multiExpr => "`|` followed by another expression",
+        _ => "an expression",
+    }
+    .to_string()
+}
+
+fn enumerate(items: &[String]) -> String {
+    match items {
+        [] => String::from("something else"),
+        [one] => one.to_string(),
+        [init @ .., last] => format!("{} or {}", init.join(", "), last),
+    }
+}
+
+/// Every bare `=` inside `{ }`, as in `{ name = expr }`, which should be `:=`. A comparison in a
+/// property's value has one too, so it's up to the caller to pick the one parsing failed at.
+fn bare_assignments(input: &str) -> Vec<usize> {
+    let mut found = vec![];
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut prev = ' ';
+    for (i, c) in input.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            _ if in_string => (),
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            '=' if depth > 0 && !":!<>=".contains(prev) => found.push(i),
+            _ => (),
+        }
+        prev = c;
+    }
+    found
+}
+
+/// Whether parsing that failed at `failed` did so on the bare `=` at `pos`, or on the name of the
+/// property it stands after, where `:=` was expected.
+fn assigns_at(input: &str, failed: usize, pos: usize) -> bool {
+    let name = match input.get(failed..pos) {
+        Some(before) => before.trim(),
+        None => return false,
+    };
+    let quoted = name.len() > 1 && name.starts_with('"') && name.ends_with('"');
+    quoted
+        || name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '$')
+}
+
+const QUOTED_KEY_HINT: &str =
+    "keys with characters other than letters, digits, `_` and `$` need quotes, e.g. `\"Content-Type\"`";
+
+/// Guesses at the fix for common mistakes, looking at the query text around the failure.
+fn suggest_fix(input: &str, pos: usize) -> Option<String> {
+    let unclosed =
+        |open: &str, close: &str| input.matches(open).count() > input.matches(close).count();
+
+    if input[pos..].starts_with('=') {
+        Some(String::from(
+            "did you mean `=`? equality is written with a single `=`",
+        ))
+    } else if input.matches('"').count() % 2 == 1 {
+        Some(String::from("string values need a closing `\"`"))
+    } else if input[pos..].starts_with('#') {
+        Some(String::from(
+            "records can only be selected with `#[...]` at the start of a query",
+        ))
+    } else if input[pos..].starts_with(|c| "-@/%".contains(c)) {
+        Some(String::from(QUOTED_KEY_HINT))
+    } else if unclosed("[", "]") {
+        Some(String::from("index access needs a closing `]`"))
+    } else if unclosed("{", "}") {
+        Some(String::from("object expressions need a closing `}`"))
+    } else if unclosed("(", ")") {
+        Some(String::from(
+            "grouped filters and function arguments need a closing `)`",
+        ))
+    } else {
+        None
+    }
+}
+
+fn syntax_error(input: &str, e: pest::error::Error<Rule>) -> JqrError {
+    let span = match e.location {
+        InputLocation::Pos(p) => (p, p),
+        InputLocation::Span(s) => s,
+    };
+
+    if let Some(pos) = bare_assignments(input)
+        .into_iter()
+        .find(|pos| assigns_at(input, span.0, *pos))
+    {
+        return JqrError::Parse {
+            message: String::from("unexpected `=`, expected `:=`"),
+            span: (pos, pos + 1),
+            query: input.to_string(),
+            hint: Some(String::from(
+                "did you mean `:=`? properties inside `{ }` are assigned with `:=`",
+            )),
+        };
+    }
+
+    let found = match input[span.0..].chars().next() {
+        Some(c) => format!("`{}`", c),
+        None => String::from("end of query"),
+    };
+    let message = match e.variant {
+        ErrorVariant::ParsingError { positives, .. } => {
+            let mut expected: Vec<String> = vec![];
+            for d in positives.iter().map(describe_rule) {
+                if !expected.contains(&d) {
+                    expected.push(d);
+                }
+            }
+            format!("unexpected {}, expected {}", found, enumerate(&expected))
         }
-        _ => unreachable!(),
+        ErrorVariant::CustomError { message } => message,
+    };
+    JqrError::Parse {
+        message,
+        span,
+        query: input.to_string(),
+        hint: suggest_fix(input, span.0),
     }
 }
 
-pub fn parse(input: &str) -> Result<QueryCmd, Box<dyn Error>> {
-    let mut parsed = JQRParser::parse(Rule::jqExpr, input)?;
+pub fn parse(input: &str) -> Result<QueryCmd, JqrError> {
+    let mut parsed = JQRParser::parse(Rule::query, input).map_err(|e| syntax_error(input, e))?;
 
-    let err: Box<dyn Error> = String::from("Empty top level parse result").into();
-    let expr = parsed.next().ok_or(err)?;
-    // println!("parsed: {:?}\n, expr: {:?}\n, rule: {:?}", parsed, expr, expr.as_rule());
-    let cmd = parse_expr(expr);
-    // println!("QueryCmd: {:?}", cmd);
-    cmd
+    let expr = parsed
+        .next()
+        .ok_or_else(|| JqrError::parse("Empty top level parse result", (0, input.len())))?;
+    parse_expr(expr).map_err(|e| e.with_query(input))
 }
 
 #[cfg(test)]
 mod parser_test {
     use super::*;
+    use serde_json::json;
 
     fn run_parse(s: &str) -> QueryCmd {
         parse(s).expect("Parse failed")
@@ -171,28 +943,39 @@ mod parser_test {
         );
         assert_eq!(
             run_parse("[11..15]"),
-            QueryCmd::ArrayIndexAccess(vec![11, 12, 13, 14])
+            QueryCmd::slice(Some(11), Some(15), 1)
         );
-        assert_eq!(run_parse("[15..11]"), QueryCmd::ArrayIndexAccess(vec![]));
-        assert_eq!(run_parse("[15..15]"), QueryCmd::ArrayIndexAccess(vec![]));
+        assert_eq!(
+            run_parse("[15..11]"),
+            QueryCmd::slice(Some(15), Some(11), 1)
+        );
+        assert_eq!(run_parse("[-1]"), QueryCmd::ArrayIndexAccess(vec![-1]));
 
-        assert_eq!(parse("[1,3, ea]").err().is_some(), true);
+        assert!(parse("[1,3, ea]").err().is_some());
+        assert!(matches!(
+            parse("[1,3, ea]"),
+            Err(JqrError::Parse { span: (6, 6), .. })
+        ));
 
-        assert_eq!(run_parse(".vals"), QueryCmd::ListValues);
-        assert_eq!(run_parse(".keys"), QueryCmd::ListKeys);
-        assert_eq!(run_parse(".count"), QueryCmd::Count);
+        assert_eq!(run_parse(".vals"), QueryCmd::call("vals", vec![]));
+        assert_eq!(run_parse(".keys"), QueryCmd::call("keys", vec![]));
+        assert_eq!(run_parse(".count"), QueryCmd::call("count", vec![]));
 
         assert_eq!(
             run_parse("username = \"Adam\""),
-            QueryCmd::filter_cmd(QueryCmd::keyword_access(&["username"]), "=", "Adam")
+            QueryCmd::filter_cmd(
+                QueryCmd::keyword_access(&["username"]),
+                CompareOp::Eq,
+                json!("Adam")
+            )
         );
 
         assert_eq!(
             run_parse("address = \"P Sherman 42 Wallaby Way\""),
             QueryCmd::filter_cmd(
                 QueryCmd::keyword_access(&["address"]),
-                "=",
-                "P Sherman 42 Wallaby Way"
+                CompareOp::Eq,
+                json!("P Sherman 42 Wallaby Way")
             )
         );
 
@@ -209,7 +992,7 @@ mod parser_test {
             QueryCmd::MultiCmd(vec![
                 QueryCmd::ArrayIndexAccess(vec![230]),
                 QueryCmd::keyword_access(&["a", "b"]),
-                QueryCmd::ListValues
+                QueryCmd::call("vals", vec![])
             ])
         );
 
@@ -233,30 +1016,882 @@ mod parser_test {
                 QueryCmd::ArrayIndexAccess(vec![0]),
                 QueryCmd::filter_cmd(
                     QueryCmd::keyword_access(&["LastDate"]),
-                    "=",
-                    "2020-04-24T00:00:00Z"
+                    CompareOp::Eq,
+                    json!("2020-04-24T00:00:00Z")
                 )
             ])
         );
 
         assert_eq!(
             run_parse("LastDate = \"2020\""),
-            QueryCmd::filter_cmd(QueryCmd::keyword_access(&["LastDate"]), "=", "2020")
+            QueryCmd::filter_cmd(
+                QueryCmd::keyword_access(&["LastDate"]),
+                CompareOp::Eq,
+                json!("2020")
+            )
         );
 
         assert_eq!(
             run_parse("Clicks = 7"),
-            QueryCmd::filter_cmd(QueryCmd::keyword_access(&["Clicks"]), "=", "7")
+            QueryCmd::filter_cmd(
+                QueryCmd::keyword_access(&["Clicks"]),
+                CompareOp::Eq,
+                json!(7)
+            )
         );
 
         assert_eq!(
             run_parse("Clicks > 0"),
-            QueryCmd::filter_cmd(QueryCmd::keyword_access(&["Clicks"]), ">", "0")
+            QueryCmd::filter_cmd(
+                QueryCmd::keyword_access(&["Clicks"]),
+                CompareOp::Gt,
+                json!(0)
+            )
         );
 
         assert_eq!(
             run_parse("CTR < 0.1"),
-            QueryCmd::filter_cmd(QueryCmd::keyword_access(&["CTR"]), "<", "0.1")
+            QueryCmd::filter_cmd(
+                QueryCmd::keyword_access(&["CTR"]),
+                CompareOp::Lt,
+                json!(0.1)
+            )
+        );
+    }
+
+    #[test]
+    fn parse_slice_test() {
+        let slices = vec![
+            ("[5..]", QueryCmd::slice(Some(5), None, 1)),
+            ("[..10]", QueryCmd::slice(None, Some(10), 1)),
+            ("[-3..]", QueryCmd::slice(Some(-3), None, 1)),
+            ("[..]", QueryCmd::slice(None, None, 1)),
+            ("[::2]", QueryCmd::slice(None, None, 2)),
+            ("[1:-1]", QueryCmd::slice(Some(1), Some(-1), 1)),
+            ("[1:10:3]", QueryCmd::slice(Some(1), Some(10), 3)),
+            (
+                "[0..100000000]",
+                QueryCmd::slice(Some(0), Some(100000000), 1),
+            ),
+        ];
+        for (query, expected) in slices {
+            assert_eq!(run_parse(query), expected, "query: {}", query);
+        }
+        assert!(parse("[::0]").is_err());
+        assert!(parse("[::-1]").is_err());
+        assert!(parse("[1..2..3]").is_err());
+
+        let bounds = |start, end| Slice {
+            start,
+            end,
+            step: 1,
+        };
+        assert_eq!(bounds(Some(2), Some(5)).bounds(10), (2, 5));
+        assert_eq!(bounds(Some(-3), None).bounds(10), (7, 10));
+        assert_eq!(bounds(None, Some(-1)).bounds(10), (0, 9));
+        assert_eq!(bounds(Some(-20), Some(20)).bounds(10), (0, 10));
+        assert_eq!(bounds(Some(15), Some(11)).bounds(20), (15, 15));
+    }
+
+    #[test]
+    fn parse_select_records_test() {
+        assert_eq!(
+            run_parse("#[0, -1]"),
+            QueryCmd::SelectRecords(Box::new(QueryCmd::ArrayIndexAccess(vec![0, -1])))
+        );
+        assert_eq!(
+            run_parse("#[10..] | a > 1 | [0]"),
+            QueryCmd::MultiCmd(vec![
+                QueryCmd::SelectRecords(Box::new(QueryCmd::slice(Some(10), None, 1))),
+                QueryCmd::filter_cmd(QueryCmd::keyword_access(&["a"]), CompareOp::Gt, json!(1)),
+                QueryCmd::ArrayIndexAccess(vec![0])
+            ])
+        );
+        match parse("a | #[0]") {
+            Err(JqrError::Parse { span, hint, .. }) => {
+                assert_eq!(span, (4, 4));
+                assert!(hint.expect("hint expected").contains("start of a query"));
+            }
+            r => panic!("expected parse error, got: {:?}", r),
+        }
+        assert!(parse("{ a := #[0] }").is_err());
+        assert!(parse("#a").is_err());
+    }
+
+    #[test]
+    fn parse_keyword_test() {
+        let keys = vec![
+            ("node_id", vec!["node_id"]),
+            ("$ref", vec!["$ref"]),
+            ("größe.wert", vec!["größe", "wert"]),
+            ("\"Content-Type\".value", vec!["Content-Type", "value"]),
+            ("headers.\"@timestamp\"", vec!["headers", "@timestamp"]),
+            ("[\"weird key\"]", vec!["weird key"]),
+            ("a[\"b.c\"].d", vec!["a", "b.c", "d"]),
+            ("[\"say \\\"hi\\\"\"]", vec!["say \"hi\""]),
+        ];
+        for (query, expected) in keys {
+            assert_eq!(
+                run_parse(query),
+                QueryCmd::keyword_access(&expected),
+                "query: {}",
+                query
+            );
+        }
+        assert_eq!(
+            run_parse("{ \"Content-Type\" := headers.\"Content-Type\"; user_id := id }"),
+            QueryCmd::TransformIntoObject(vec![
+                (
+                    "Content-Type".to_string(),
+                    QueryCmd::keyword_access(&["headers", "Content-Type"])
+                ),
+                ("user_id".to_string(), QueryCmd::keyword_access(&["id"]))
+            ])
+        );
+        assert_eq!(
+            run_parse("[\"Content-Type\"] = \"text/html\""),
+            QueryCmd::filter_cmd(
+                QueryCmd::keyword_access(&["Content-Type"]),
+                CompareOp::Eq,
+                json!("text/html")
+            )
+        );
+        // a quoted string on its own is a string, wherever it is
+        let text = || QueryCmd::Literal(json!("Content-Type"));
+        assert_eq!(run_parse("\"Content-Type\""), text());
+        assert_eq!(
+            run_parse("a | \"Content-Type\""),
+            QueryCmd::MultiCmd(vec![QueryCmd::keyword_access(&["a"]), text()])
+        );
+        assert_eq!(
+            run_parse("{ t := \"Content-Type\" }"),
+            QueryCmd::TransformIntoObject(vec![(String::from("t"), text())])
+        );
+        assert_eq!(
+            run_parse("\"Content-Type\" = \"text/html\""),
+            QueryCmd::FilterCmd(Predicate::Compare(
+                Box::new(text()),
+                CompareOp::Eq,
+                Box::new(QueryCmd::Literal(json!("text/html")))
+            ))
+        );
+        match parse("headers.Content-Type") {
+            Err(JqrError::Parse { span, hint, .. }) => {
+                assert_eq!(span, (15, 15));
+                assert!(hint.expect("hint expected").contains("quotes"));
+            }
+            r => panic!("expected parse error, got: {:?}", r),
+        }
+        assert!(parse("@timestamp").is_err());
+    }
+
+    #[test]
+    fn parse_path_search_test() {
+        let key = |k: &str| PathSegment::Key(k.to_string());
+        let paths = vec![
+            ("..name", vec![PathSegment::AnyDepth, key("name")]),
+            (
+                "a..\"b-c\"",
+                vec![key("a"), PathSegment::AnyDepth, key("b-c")],
+            ),
+            ("*", vec![PathSegment::AnyChild]),
+            ("a.*.b", vec![key("a"), PathSegment::AnyChild, key("b")]),
+            ("a.**", vec![key("a"), PathSegment::AnyDepth]),
+            (
+                "**.id[\"x\"]",
+                vec![PathSegment::AnyDepth, key("id"), key("x")],
+            ),
+        ];
+        for (query, expected) in paths {
+            assert_eq!(
+                run_parse(query),
+                QueryCmd::PathSearch(expected),
+                "query: {}",
+                query
+            );
+        }
+        assert_eq!(run_parse("a.b"), QueryCmd::keyword_access(&["a", "b"]));
+        assert!(parse("a...b").is_err());
+        assert!(parse("a*").is_err());
+    }
+
+    #[test]
+    fn parse_alternative_test() {
+        assert_eq!(
+            run_parse("a.b?"),
+            QueryCmd::Optional(Box::new(QueryCmd::keyword_access(&["a", "b"])))
+        );
+        assert_eq!(
+            run_parse("[5]?"),
+            QueryCmd::Optional(Box::new(QueryCmd::ArrayIndexAccess(vec![5])))
+        );
+        assert_eq!(
+            run_parse("[-2..]? // 0"),
+            QueryCmd::Alternative(vec![
+                QueryCmd::Optional(Box::new(QueryCmd::slice(Some(-2), None, 1))),
+                QueryCmd::Literal(json!(0))
+            ])
+        );
+        assert_eq!(
+            run_parse("nick? // name // \"N/A\""),
+            QueryCmd::Alternative(vec![
+                QueryCmd::Optional(Box::new(QueryCmd::keyword_access(&["nick"]))),
+                QueryCmd::keyword_access(&["name"]),
+                QueryCmd::Literal(json!("N/A"))
+            ])
+        );
+        assert_eq!(
+            run_parse("{ n := count // 0; t := tag? }"),
+            QueryCmd::TransformIntoObject(vec![
+                (
+                    "n".to_string(),
+                    QueryCmd::Alternative(vec![
+                        QueryCmd::keyword_access(&["count"]),
+                        QueryCmd::Literal(json!(0))
+                    ])
+                ),
+                (
+                    "t".to_string(),
+                    QueryCmd::Optional(Box::new(QueryCmd::keyword_access(&["tag"])))
+                )
+            ])
+        );
+        assert_eq!(
+            run_parse("score // 0 > 5"),
+            QueryCmd::filter_cmd(
+                QueryCmd::Alternative(vec![
+                    QueryCmd::keyword_access(&["score"]),
+                    QueryCmd::Literal(json!(0))
+                ]),
+                CompareOp::Gt,
+                json!(5)
+            )
+        );
+        assert_eq!(
+            run_parse("a // nullable"),
+            QueryCmd::Alternative(vec![
+                QueryCmd::keyword_access(&["a"]),
+                QueryCmd::keyword_access(&["nullable"])
+            ])
+        );
+        assert!(parse("a //").is_err());
+    }
+
+    #[test]
+    fn parse_compare_op_test() {
+        let ops = vec![
+            ("=", CompareOp::Eq),
+            ("!=", CompareOp::Ne),
+            ("<", CompareOp::Lt),
+            ("<=", CompareOp::Le),
+            (">", CompareOp::Gt),
+            (">=", CompareOp::Ge),
+        ];
+        for (op, expected) in ops {
+            assert_eq!(
+                run_parse(&format!("Clicks {} 3", op)),
+                QueryCmd::filter_cmd(QueryCmd::keyword_access(&["Clicks"]), expected, json!(3)),
+                "op: {}",
+                op
+            );
+        }
+        assert!(CompareOp::Le.holds(Ordering::Equal));
+        assert!(!CompareOp::Ge.holds(Ordering::Less));
+        assert!(parse("Clicks => 3").is_err());
+        assert!(matches!(
+            parse("{ a := b != 1 }"),
+            Ok(QueryCmd::TransformIntoObject(_))
+        ));
+    }
+
+    #[test]
+    fn parse_literal_test() {
+        let literals = vec![
+            ("true", json!(true)),
+            ("false", json!(false)),
+            ("null", json!(null)),
+            ("-5", json!(-5)),
+            ("-0.25", json!(-0.25)),
+            ("1e3", json!(1000.0)),
+            ("2.5E-1", json!(0.25)),
+            ("\"a.b/c@d e\"", json!("a.b/c@d e")),
+            ("\"say \\\"hi\\\"\"", json!("say \"hi\"")),
+            ("\"tab\\tand \\u00e9\"", json!("tab\tand \u{e9}")),
+            ("\"\"", json!("")),
+        ];
+        for (literal, expected) in literals {
+            assert_eq!(
+                run_parse(&format!("a = {}", literal)),
+                QueryCmd::filter_cmd(QueryCmd::keyword_access(&["a"]), CompareOp::Eq, expected),
+                "literal: {}",
+                literal
+            );
+        }
+        // keys that merely start like a literal are compared against
+        assert_eq!(
+            run_parse("b = nullable"),
+            QueryCmd::FilterCmd(Predicate::Compare(
+                Box::new(QueryCmd::keyword_access(&["b"])),
+                CompareOp::Eq,
+                Box::new(QueryCmd::keyword_access(&["nullable"]))
+            ))
+        );
+        assert!(parse("a = \"bad \\q escape\"").is_err());
+        assert!(parse("a = 1e").is_err());
+    }
+
+    #[test]
+    fn parse_field_comparison_test() {
+        let key = |k: &str| Box::new(QueryCmd::keyword_access(&[k]));
+        let compare = |lhs, op, rhs| QueryCmd::FilterCmd(Predicate::Compare(lhs, op, rhs));
+        assert_eq!(
+            run_parse("Clicks > Impressions"),
+            compare(key("Clicks"), CompareOp::Gt, key("Impressions"))
+        );
+        assert_eq!(
+            run_parse("updated_at >= meta.\"created-at\""),
+            compare(
+                key("updated_at"),
+                CompareOp::Ge,
+                Box::new(QueryCmd::keyword_access(&["meta", "created-at"]))
+            )
+        );
+        assert_eq!(
+            run_parse("Clicks * 10 > Impressions + 5"),
+            compare(
+                Box::new(QueryCmd::arithmetic(
+                    *key("Clicks"),
+                    ArithOp::Mul,
+                    QueryCmd::Literal(json!(10))
+                )),
+                CompareOp::Gt,
+                Box::new(QueryCmd::arithmetic(
+                    *key("Impressions"),
+                    ArithOp::Add,
+                    QueryCmd::Literal(json!(5))
+                ))
+            )
+        );
+        // a quoted string is a string on either side, a key in brackets or followed by a path
+        assert_eq!(
+            run_parse("[\"a-b\"] != \"a-b\".c"),
+            compare(
+                key("a-b"),
+                CompareOp::Ne,
+                Box::new(QueryCmd::keyword_access(&["a-b", "c"]))
+            )
+        );
+        assert_eq!(
+            run_parse("tags | .length > limit? // 3"),
+            QueryCmd::MultiCmd(vec![
+                *key("tags"),
+                compare(
+                    Box::new(QueryCmd::call("length", vec![])),
+                    CompareOp::Gt,
+                    Box::new(QueryCmd::Alternative(vec![
+                        QueryCmd::Optional(Box::new(*key("limit"))),
+                        QueryCmd::Literal(json!(3))
+                    ]))
+                )
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_conditional_test() {
+        let key = |k: &str| QueryCmd::keyword_access(&[k]);
+        let lit = |v: Value| QueryCmd::Literal(v);
+        let ctr_above = |v: Value| Predicate::compare(key("CTR"), CompareOp::Gt, v);
+        assert_eq!(
+            run_parse("if CTR > 0.05 then \"good\" else \"bad\" end"),
+            QueryCmd::Conditional(
+                vec![(ctr_above(json!(0.05)), lit(json!("good")))],
+                Some(Box::new(lit(json!("bad"))))
+            )
+        );
+        assert_eq!(
+            run_parse(
+                "{ label := if CTR > 0.05 then \"good\" elif CTR > 0.01 and not x = 1 then name end }"
+            ),
+            QueryCmd::TransformIntoObject(vec![(
+                String::from("label"),
+                QueryCmd::Conditional(
+                    vec![
+                        (ctr_above(json!(0.05)), lit(json!("good"))),
+                        (
+                            Predicate::And(
+                                Box::new(ctr_above(json!(0.01))),
+                                Box::new(Predicate::Not(Box::new(Predicate::compare(
+                                    key("x"),
+                                    CompareOp::Eq,
+                                    json!(1)
+                                ))))
+                            ),
+                            key("name")
+                        )
+                    ],
+                    None
+                )
+            )])
+        );
+        // branches are whole queries, and a conditional is an operand like any other
+        assert_eq!(
+            run_parse("if a < b then tags | .length else 0 end * 2"),
+            QueryCmd::arithmetic(
+                QueryCmd::Conditional(
+                    vec![(
+                        Predicate::Compare(Box::new(key("a")), CompareOp::Lt, Box::new(key("b"))),
+                        QueryCmd::MultiCmd(vec![key("tags"), QueryCmd::call("length", vec![])])
+                    )],
+                    Some(Box::new(lit(json!(0))))
+                ),
+                ArithOp::Mul,
+                lit(json!(2))
+            )
+        );
+        // `if` on its own, or as part of a longer name, is still a key
+        assert_eq!(run_parse("if"), key("if"));
+        assert_eq!(
+            run_parse("iffy | endless"),
+            QueryCmd::MultiCmd(vec![key("iffy"), key("endless")])
+        );
+        assert!(parse("if a > 1 then 1").is_err());
+        assert!(parse("if a then 1 end").is_err());
+        assert!(parse("if a > 1 then 1 else 2 elif a > 2 then 3 end").is_err());
+    }
+
+    #[test]
+    fn parse_predicate_test() {
+        let cmp = |k: &str, op: CompareOp, v: Value| {
+            Box::new(Predicate::compare(QueryCmd::keyword_access(&[k]), op, v))
+        };
+
+        assert_eq!(
+            run_parse("Clicks > 0 and CTR < 0.1"),
+            QueryCmd::FilterCmd(Predicate::And(
+                cmp("Clicks", CompareOp::Gt, json!(0)),
+                cmp("CTR", CompareOp::Lt, json!(0.1))
+            ))
+        );
+        // `and` binds tighter than `or`, `not` tighter than both
+        assert_eq!(
+            run_parse("a = 1 or not b = 2 and c = 3"),
+            QueryCmd::FilterCmd(Predicate::Or(
+                cmp("a", CompareOp::Eq, json!(1)),
+                Box::new(Predicate::And(
+                    Box::new(Predicate::Not(cmp("b", CompareOp::Eq, json!(2)))),
+                    cmp("c", CompareOp::Eq, json!(3))
+                ))
+            ))
+        );
+        assert_eq!(
+            run_parse("(a = 1 or b = 2) and c = 3"),
+            QueryCmd::FilterCmd(Predicate::And(
+                Box::new(Predicate::Or(
+                    cmp("a", CompareOp::Eq, json!(1)),
+                    cmp("b", CompareOp::Eq, json!(2))
+                )),
+                cmp("c", CompareOp::Eq, json!(3))
+            ))
+        );
+        assert_eq!(
+            run_parse("a = 1 or b = 2 or c = 3"),
+            QueryCmd::FilterCmd(Predicate::Or(
+                Box::new(Predicate::Or(
+                    cmp("a", CompareOp::Eq, json!(1)),
+                    cmp("b", CompareOp::Eq, json!(2))
+                )),
+                cmp("c", CompareOp::Eq, json!(3))
+            ))
+        );
+        // keywords only count as operators when they are whole words
+        assert_eq!(
+            run_parse("notes = 1 and order > 2"),
+            QueryCmd::FilterCmd(Predicate::And(
+                cmp("notes", CompareOp::Eq, json!(1)),
+                cmp("order", CompareOp::Gt, json!(2))
+            ))
+        );
+        assert_eq!(
+            run_parse("not = 1"),
+            QueryCmd::filter_cmd(QueryCmd::keyword_access(&["not"]), CompareOp::Eq, json!(1))
+        );
+        assert_eq!(
+            run_parse("[0] | a = 1 and b = 2 | c"),
+            QueryCmd::MultiCmd(vec![
+                QueryCmd::ArrayIndexAccess(vec![0]),
+                QueryCmd::FilterCmd(Predicate::And(
+                    cmp("a", CompareOp::Eq, json!(1)),
+                    cmp("b", CompareOp::Eq, json!(2))
+                )),
+                QueryCmd::keyword_access(&["c"])
+            ])
+        );
+
+        assert!(parse("a = 1 and").is_err());
+        match parse("(a = 1 or b = 2") {
+            Err(JqrError::Parse { hint, .. }) => {
+                assert!(hint.expect("hint expected").contains("`)`"))
+            }
+            r => panic!("expected parse error, got: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn parse_function_test() {
+        assert_eq!(run_parse(".length"), QueryCmd::call("length", vec![]));
+        assert_eq!(run_parse("length()"), QueryCmd::call("length", vec![]));
+        assert_eq!(
+            run_parse("has(\"a-b\")"),
+            QueryCmd::call("has", vec![QueryCmd::Literal(json!("a-b"))])
+        );
+        assert_eq!(
+            run_parse("contains(tags | [0])"),
+            QueryCmd::call(
+                "contains",
+                vec![QueryCmd::MultiCmd(vec![
+                    QueryCmd::keyword_access(&["tags"]),
+                    QueryCmd::ArrayIndexAccess(vec![0])
+                ])]
+            )
+        );
+        assert_eq!(
+            run_parse("items | .sum"),
+            QueryCmd::MultiCmd(vec![
+                QueryCmd::keyword_access(&["items"]),
+                QueryCmd::call("sum", vec![])
+            ])
+        );
+        assert_eq!(
+            run_parse("has(\"x\") = true and .length > 2"),
+            QueryCmd::FilterCmd(Predicate::And(
+                Box::new(Predicate::compare(
+                    QueryCmd::call("has", vec![QueryCmd::Literal(json!("x"))]),
+                    CompareOp::Eq,
+                    json!(true)
+                )),
+                Box::new(Predicate::compare(
+                    QueryCmd::call("length", vec![]),
+                    CompareOp::Gt,
+                    json!(2)
+                ))
+            ))
+        );
+        assert_eq!(
+            run_parse("tags.first // .length"),
+            QueryCmd::Alternative(vec![
+                QueryCmd::keyword_access(&["tags", "first"]),
+                QueryCmd::call("length", vec![])
+            ])
+        );
+        let substr = QueryCmd::call(
+            "substr",
+            vec![QueryCmd::Literal(json!(0)), QueryCmd::Literal(json!(-1))],
+        );
+        assert_eq!(run_parse("substr(0; -1)"), substr);
+        assert_eq!(run_parse("substr(0, -1)"), substr);
+        // a key that happens to be named like a function is still a key
+        assert_eq!(run_parse("length"), QueryCmd::keyword_access(&["length"]));
+
+        match parse("a | .nope") {
+            Err(JqrError::Parse { span, hint, .. }) => {
+                assert_eq!(span, (5, 9));
+                assert!(hint.expect("hint expected").contains("--help"));
+            }
+            r => panic!("expected parse error, got: {:?}", r),
+        }
+        match parse(".has") {
+            Err(e @ JqrError::Parse { .. }) => {
+                assert!(e.to_string().contains("`has` takes 1 argument(s), got 0"))
+            }
+            r => panic!("expected parse error, got: {:?}", r),
+        }
+        assert!(parse("length(1)").is_err());
+        assert!(parse("has(\"a\"").is_err());
+
+        assert_eq!(
+            run_parse("top(10; Clicks)"),
+            QueryCmd::call(
+                "top",
+                vec![
+                    QueryCmd::Literal(json!(10)),
+                    QueryCmd::keyword_access(&["Clicks"])
+                ]
+            )
+        );
+        assert_eq!(
+            run_parse("sort_by(a.b; desc)"),
+            QueryCmd::call(
+                "sort_by",
+                vec![
+                    QueryCmd::keyword_access(&["a", "b"]),
+                    QueryCmd::keyword_access(&["desc"])
+                ]
+            )
+        );
+        for (query, message) in &[
+            ("sort_by()", "`sort_by` takes 1 to 2 arguments, got 0"),
+            ("sort_by(a; up)", "sort order has to be `asc` or `desc`"),
+            ("top(-1; a)", "`top` takes how many values to keep first"),
+            ("top(n; a)", "`top` takes how many values to keep first"),
+            (
+                "percentile(101)",
+                "`percentile` takes a number from 0 to 100",
+            ),
+        ] {
+            match parse(query) {
+                Err(JqrError::Parse {
+                    message: m, span, ..
+                }) => {
+                    assert!(m.contains(message), "query: {}, got: {}", query, m);
+                    assert_eq!(span, (0, query.len()), "query: {}", query);
+                }
+                r => panic!("expected parse error for {}, got: {:?}", query, r),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_group_by_test() {
+        let aggregate = |name: &str, args: Vec<QueryCmd>, path: Option<&str>| {
+            GroupField::Aggregate(
+                crate::builtin(name).unwrap(),
+                args,
+                path.map(|p| Box::new(QueryCmd::keyword_access(&[p]))),
+            )
+        };
+        assert_eq!(
+            run_parse(
+                "Clicks > 0 | group_by(AdTypeId) | { k := .key; clicks := sum(Clicks); n := count; \
+                 p := percentile(95; Ms) }"
+            ),
+            QueryCmd::MultiCmd(vec![
+                QueryCmd::filter_cmd(QueryCmd::keyword_access(&["Clicks"]), CompareOp::Gt, json!(0)),
+                QueryCmd::GroupBy(Grouping {
+                    key: Box::new(QueryCmd::keyword_access(&["AdTypeId"])),
+                    fields: vec![
+                        (String::from("k"), GroupField::Key),
+                        (String::from("clicks"), aggregate("sum", vec![], Some("Clicks"))),
+                        (String::from("n"), aggregate("count", vec![], None)),
+                        (
+                            String::from("p"),
+                            aggregate("percentile", vec![QueryCmd::Literal(json!(95))], Some("Ms"))
+                        ),
+                    ]
+                })
+            ])
+        );
+        assert_eq!(
+            run_parse("group_by(a) | { n := .count }"),
+            QueryCmd::GroupBy(Grouping {
+                key: Box::new(QueryCmd::keyword_access(&["a"])),
+                fields: vec![(String::from("n"), aggregate("count", vec![], None))]
+            })
+        );
+        // not followed by an object it's the plain function
+        assert_eq!(
+            run_parse("group_by(a) | [0]"),
+            QueryCmd::MultiCmd(vec![
+                QueryCmd::call("group_by", vec![QueryCmd::keyword_access(&["a"])]),
+                QueryCmd::ArrayIndexAccess(vec![0])
+            ])
+        );
+
+        for (query, message) in &[
+            ("group_by(a) | { n := name }", "`.key` or aggregates"),
+            ("group_by(a) | { n := .first }", "`.key` or aggregates"),
+            ("group_by(a) | { n := key(b) }", "`.key` or aggregates"),
+            (
+                "group_by(a) | { n := sum(b; c) }",
+                "`sum` takes 0 argument(s) and the path to aggregate, got 2",
+            ),
+            (
+                "group_by(a) | { n := percentile(x; c) }",
+                "`percentile` takes a number",
+            ),
+        ] {
+            match parse(query) {
+                Err(JqrError::Parse { message: m, .. }) => {
+                    assert!(m.contains(message), "query: {}, got: {}", query, m)
+                }
+                r => panic!("expected parse error for {}, got: {:?}", query, r),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_arithmetic_test() {
+        let key = |k: &str| QueryCmd::keyword_access(&[k]);
+        let lit = |v: Value| QueryCmd::Literal(v);
+        assert_eq!(
+            run_parse("Clicks / Impressions"),
+            QueryCmd::arithmetic(key("Clicks"), ArithOp::Div, key("Impressions"))
+        );
+        // `*` binds tighter than `+` and `-`, which apply left to right
+        assert_eq!(
+            run_parse("a - b + c * 2"),
+            QueryCmd::arithmetic(
+                QueryCmd::arithmetic(key("a"), ArithOp::Sub, key("b")),
+                ArithOp::Add,
+                QueryCmd::arithmetic(key("c"), ArithOp::Mul, lit(json!(2)))
+            )
         );
+        assert_eq!(
+            run_parse("(a + 1) % 3"),
+            QueryCmd::arithmetic(
+                QueryCmd::arithmetic(key("a"), ArithOp::Add, lit(json!(1))),
+                ArithOp::Rem,
+                lit(json!(3))
+            )
+        );
+        assert_eq!(
+            run_parse("{ name := first + \" \" + last }"),
+            QueryCmd::TransformIntoObject(vec![(
+                String::from("name"),
+                QueryCmd::arithmetic(
+                    QueryCmd::arithmetic(key("first"), ArithOp::Add, lit(json!(" "))),
+                    ArithOp::Add,
+                    key("last")
+                )
+            )])
+        );
+        assert_eq!(
+            run_parse("Clicks / Impressions >= 0.05"),
+            QueryCmd::filter_cmd(
+                QueryCmd::arithmetic(key("Clicks"), ArithOp::Div, key("Impressions")),
+                CompareOp::Ge,
+                json!(0.05)
+            )
+        );
+        // `//` is still the alternative operator, and binds tighter
+        assert_eq!(
+            run_parse("a // 0 + b.* * 1"),
+            QueryCmd::arithmetic(
+                QueryCmd::Alternative(vec![key("a"), lit(json!(0))]),
+                ArithOp::Add,
+                QueryCmd::arithmetic(
+                    QueryCmd::PathSearch(vec![
+                        PathSegment::Key(String::from("b")),
+                        PathSegment::AnyChild
+                    ]),
+                    ArithOp::Mul,
+                    lit(json!(1))
+                )
+            )
+        );
+        // quoted strings mean the same as anywhere else, a string unless a path follows
+        assert_eq!(
+            run_parse("\"Content-Type\" + 1"),
+            QueryCmd::arithmetic(lit(json!("Content-Type")), ArithOp::Add, lit(json!(1)))
+        );
+        assert_eq!(
+            run_parse("[\"Content-Type\"] + \"a-b\".c"),
+            QueryCmd::arithmetic(
+                key("Content-Type"),
+                ArithOp::Add,
+                QueryCmd::keyword_access(&["a-b", "c"])
+            )
+        );
+        assert!(parse("a +").is_err());
+        assert!(parse("a + * 2").is_err());
+        assert!(parse("Clicks-Impressions").is_err());
+        assert!(parse("Clicks-1").is_ok());
+    }
+
+    #[test]
+    fn parse_regex_test() {
+        let pattern = |re: &str| Pattern(Regex::new(re).unwrap());
+        assert_eq!(
+            run_parse("msg ~ \"^GET\""),
+            QueryCmd::FilterCmd(Predicate::Matches(
+                Box::new(QueryCmd::keyword_access(&["msg"])),
+                pattern("^GET")
+            ))
+        );
+        assert_eq!(
+            run_parse("level matches \"a\\\\d\""),
+            QueryCmd::FilterCmd(Predicate::Matches(
+                Box::new(QueryCmd::keyword_access(&["level"])),
+                pattern("a\\d")
+            ))
+        );
+        assert_eq!(
+            run_parse("sub(\"a+\"; \"b\")"),
+            QueryCmd::call(
+                "sub",
+                vec![
+                    QueryCmd::Pattern(pattern("a+")),
+                    QueryCmd::Literal(json!("b"))
+                ]
+            )
+        );
+        // `matches` is only an operator after a path, on its own it's a key
+        assert_eq!(run_parse("matches"), QueryCmd::keyword_access(&["matches"]));
+
+        match parse("msg | test(\"[0-9\")") {
+            Err(e @ JqrError::Parse { .. }) => {
+                assert!(e
+                    .to_string()
+                    .contains("invalid regex, unclosed character class"));
+                assert!(e.to_string().contains("  |            ^^^^^^"));
+            }
+            r => panic!("expected parse error, got: {:?}", r),
+        }
+        assert!(parse("test(pattern)").is_err());
+        assert!(parse("msg ~ 5").is_err());
+    }
+
+    #[test]
+    fn parse_error_test() {
+        let assignments = vec![
+            ("[0] | { a := b; c = d }", 18),
+            ("{a := 1; b = 2}", 11),
+            ("{ a := x = 1; b = 2 }", 16),
+            ("{ a := \"x\\\"y = z\"; b = 2 }", 21),
+            ("{ a := \"\\\\\"; b = 2 }", 15),
+            ("{ a := x >= 1 and y != \"{\"; b = 2 }", 30),
+            ("{ a := 1; \"b c\" = 2 }", 16),
+        ];
+        for (query, pos) in assignments {
+            match parse(query) {
+                Err(JqrError::Parse { span, hint, .. }) => {
+                    assert_eq!(span, (pos, pos + 1), "query: {}", query);
+                    assert!(hint.expect("hint expected").contains(":="));
+                }
+                r => panic!("expected parse error for {}, got: {:?}", query, r),
+            }
+        }
+
+        match parse("name = \"abc") {
+            Err(e @ JqrError::Parse { .. }) => {
+                let rendered = e.to_string();
+                assert!(rendered.contains("  | name = \"abc\n  |        ^"));
+                assert!(
+                    rendered.contains("expected a value to compare with: string, number, boolean")
+                );
+                assert!(rendered.contains("help: string values need a closing"));
+            }
+            r => panic!("expected parse error, got: {:?}", r),
+        }
+
+        assert!(matches!(
+            parse("a == 3"),
+            Err(JqrError::Parse { span: (3, 3), .. })
+        ));
+
+        // a valid comparison after the real mistake isn't taken for a misplaced assignment
+        match parse("{ x := a +; y := b = 1 }") {
+            Err(JqrError::Parse {
+                message,
+                span,
+                hint,
+                ..
+            }) => {
+                assert!(message.starts_with("unexpected `;`"), "got: {}", message);
+                assert_eq!(span, (10, 10));
+                assert!(!hint.unwrap_or_default().contains(":="));
+            }
+            r => panic!("expected parse error, got: {:?}", r),
+        }
     }
 }